@@ -5,4 +5,5 @@ mod benchmarks;
 criterion_main! {
     benchmarks::default_store::benches,
     benchmarks::cf_store::benches,
+    benchmarks::serde::benches,
 }
@@ -5,8 +5,8 @@ use rocksdb::{
     OptimisticTransactionDB, Options,
 };
 
-use smt_rocksdb_store::cf_store::{ColumnFamilyStore, ColumnFamilyStoreMultiTree};
 use sparse_merkle_tree::{blake2b::Blake2bHasher, SparseMerkleTree};
+use sub_account_store::cf_store::{ColumnFamilyStore, ColumnFamilyStoreMultiTree};
 use tempfile::{Builder, TempDir};
 
 use super::{random_kvs, V};
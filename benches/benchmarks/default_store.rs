@@ -2,8 +2,8 @@ use criterion::{criterion_group, BenchmarkId, Criterion};
 use rand::{seq::IteratorRandom, thread_rng};
 use rocksdb::{prelude::Open, OptimisticTransactionDB};
 
-use smt_rocksdb_store::default_store::{DefaultStore, DefaultStoreMultiTree};
 use sparse_merkle_tree::{blake2b::Blake2bHasher, SparseMerkleTree};
+use sub_account_store::default_store::{DefaultStore, DefaultStoreMultiTree};
 use tempfile::{Builder, TempDir};
 
 use super::{random_kvs, V};
@@ -4,6 +4,7 @@ use sparse_merkle_tree::{traits::Value, H256};
 
 pub mod cf_store;
 pub mod default_store;
+pub mod serde;
 
 #[derive(Default, Clone)]
 pub struct V([u8; 32]);
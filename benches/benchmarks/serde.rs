@@ -0,0 +1,73 @@
+use criterion::{criterion_group, BenchmarkId, Criterion, Throughput};
+use sparse_merkle_tree::{
+    blake2b::Blake2bHasher, default_store::DefaultStore, BranchNode, SparseMerkleTree,
+};
+
+use sub_account_store::serde::{
+    branch_node_to_vec, branch_node_to_vec_compact, try_slice_to_branch_node,
+};
+
+use super::{random_kvs, V};
+
+type MemoryStoreSMT = SparseMerkleTree<Blake2bHasher, V, DefaultStore<V>>;
+
+// the branch nodes of a tree with `count` random leaves
+fn branch_nodes(count: usize) -> Vec<BranchNode> {
+    let mut smt = MemoryStoreSMT::new_with_store(Default::default()).unwrap();
+    smt.update_all(random_kvs(count)).unwrap();
+    smt.store().branches_map().values().cloned().collect()
+}
+
+fn benchmark(c: &mut Criterion) {
+    let encodings: [(&str, fn(&BranchNode) -> Vec<u8>); 2] = [
+        ("full", branch_node_to_vec),
+        ("compact", branch_node_to_vec_compact),
+    ];
+
+    for count in [1000, 10000] {
+        let nodes = branch_nodes(count);
+        for (name, encode) in encodings {
+            let size: usize = nodes.iter().map(|n| encode(n).len()).sum();
+            println!(
+                "{} encoding of {} branch nodes ({} leaves): {} bytes, {:.1} bytes per node",
+                name,
+                nodes.len(),
+                count,
+                size,
+                size as f64 / nodes.len() as f64
+            );
+        }
+    }
+
+    let mut group = c.benchmark_group("branch_node_encode");
+    for count in [1000, 10000] {
+        let nodes = branch_nodes(count);
+        group.throughput(Throughput::Elements(nodes.len() as u64));
+        for (name, encode) in encodings {
+            group.bench_with_input(BenchmarkId::new(name, count), &nodes, |b, nodes| {
+                b.iter(|| nodes.iter().map(encode).collect::<Vec<_>>())
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("branch_node_decode");
+    for count in [1000, 10000] {
+        let nodes = branch_nodes(count);
+        group.throughput(Throughput::Elements(nodes.len() as u64));
+        for (name, encode) in encodings {
+            let records: Vec<Vec<u8>> = nodes.iter().map(encode).collect();
+            group.bench_with_input(BenchmarkId::new(name, count), &records, |b, records| {
+                b.iter(|| {
+                    records
+                        .iter()
+                        .map(|r| try_slice_to_branch_node(r).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark);
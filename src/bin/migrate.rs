@@ -0,0 +1,100 @@
+use clap::Parser;
+use log::{error, info};
+use rocksdb::{
+    prelude::{GetColumnFamilys, Open, OpenCF},
+    OptimisticTransactionDB, Options, DB,
+};
+use std::path::Path;
+use sub_account_store::blake2b::Blake2bHasherCustom;
+use sub_account_store::cf_store::repair_stray_leaves;
+use sub_account_store::migration::{
+    migrate_legacy_cf_database, migrate_legacy_database, migrate_legacy_tree_versions,
+    LEGACY_VERSION_DB_DIR,
+};
+use sub_account_store::structures::SmtValue;
+use sub_account_store::tree_store::{BRANCH_CF, LEAF_CF};
+use sub_account_store::utils::slice_to_hex_string;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about = "Migrate a database to the current schema version", long_about = None)]
+struct Args {
+    //database path of rocksdb
+    #[clap(short, long, default_value = "/tmp/smt-store")]
+    db_path: String,
+
+    //remove the records of the legacy layout after all the trees are migrated
+    #[clap(long)]
+    purge: bool,
+}
+
+// a database written by `ColumnFamilyStoreMultiTree` has to be opened with its column families
+fn open_database(db_path: &str) -> Result<OptimisticTransactionDB, rocksdb::Error> {
+    let cfs = DB::list_cf(&Options::default(), db_path).unwrap_or_default();
+    if cfs.iter().any(|name| name == BRANCH_CF) && cfs.iter().any(|name| name == LEAF_CF) {
+        OptimisticTransactionDB::open_cf(&Options::default(), db_path, vec![BRANCH_CF, LEAF_CF])
+    } else {
+        OptimisticTransactionDB::open_default(db_path)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let args = Args::parse();
+
+    info!("opening database");
+    let db = match open_database(&args.db_path) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("cannot open database :{}", &e);
+            return Err(e.into());
+        }
+    };
+
+    info!("migrating database {}", &args.db_path);
+    let migrated = match (db.cf_handle(BRANCH_CF), db.cf_handle(LEAF_CF)) {
+        (Some(branch_col), Some(leaf_col)) => {
+            info!("the database has the column family layout");
+            // the legacy leaves are read from the leaf column family
+            repair_stray_leaves::<Blake2bHasherCustom, SmtValue>(&db, branch_col, leaf_col)
+                .and_then(|moved| {
+                    info!("stray leaves moved num = {}", moved);
+                    migrate_legacy_cf_database::<Blake2bHasherCustom, SmtValue>(
+                        &db, branch_col, leaf_col, args.purge,
+                    )
+                })
+        }
+        _ => migrate_legacy_database::<Blake2bHasherCustom, SmtValue>(&db, args.purge),
+    };
+    let migrated = match migrated {
+        Ok(m) => m,
+        Err(e) => {
+            error!("migration failed, the database is left unmigrated : {}", &e);
+            return Err(e);
+        }
+    };
+    for (name, root) in migrated.iter() {
+        info!(
+            "migrated tree {}, root = {}",
+            String::from_utf8_lossy(name),
+            slice_to_hex_string(root.as_slice())
+        );
+    }
+    info!("migration success, trees num = {}", migrated.len());
+
+    // the versions recorded in the legacy version database are only read by the migration
+    let version_db_path = Path::new(&args.db_path).join(LEGACY_VERSION_DB_DIR);
+    let versions = match migrate_legacy_tree_versions(&db, &version_db_path) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("cannot migrate legacy tree versions : {}", &e);
+            return Err(e);
+        }
+    };
+    info!(
+        "legacy tree versions migrated num = {}, {} can be removed",
+        versions.len(),
+        version_db_path.display()
+    );
+    Ok(())
+}
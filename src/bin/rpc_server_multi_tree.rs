@@ -1,9 +1,20 @@
 use clap::Parser;
 use jsonrpsee::http_server::HttpServerBuilder;
-use log::{error, info};
-use rocksdb::{prelude::Open, OptimisticTransactionDB};
+use log::{error, info, warn};
+use rocksdb::{
+    prelude::{Open, OpenCF},
+    BlockBasedOptions, Cache, ColumnFamilyDescriptor, OptimisticTransactionDB, Options, DB,
+};
 use std::net::SocketAddr;
+use std::path::Path;
+use sub_account_store::migration::LEGACY_VERSION_DB_DIR;
 use sub_account_store::rpc_server::{RpcServer, RpcServerImpl};
+use sub_account_store::schema::check_metadata;
+use sub_account_store::serde::BranchNodeEncoding;
+use sub_account_store::tree_store::{
+    ColumnFamilyBackend, StoreBackend, StoreLayout, TreeColumnFamilyBackend, BRANCH_CF, LEAF_CF,
+    TREE_CF_PREFIX,
+};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -15,6 +26,89 @@ struct Args {
     //database path of rocksdb
     #[clap(short, long, default_value = "/tmp/smt-store")]
     db_path: String,
+
+    //encoding of the branch nodes, `full` or `compact`, only used when a new database is created
+    #[clap(long, default_value = "full")]
+    branch_node_encoding: BranchNodeEncoding,
+
+    //layout of the trees, `default`, `column_family` or `tree_column_family`, a database must be opened with the layout
+    //it's created with
+    #[clap(long, default_value = "default")]
+    store_layout: StoreLayout,
+
+    //block cache size in MB of each column family of the `column_family` layout, and of all the trees of the
+    //`tree_column_family` layout
+    #[clap(long, default_value = "256")]
+    block_cache_size: usize,
+
+    //number of versions of each tree retained to serve reads at past roots, the history is disabled if it's 0
+    #[clap(long, default_value = "0")]
+    history_window: u64,
+}
+
+// the options of the column families, the branch nodes are read on every update and proof, while the leaves are mostly
+// read by key lookups, so each column family has a bloom filter, and the blocks are cached in `cache`
+fn column_family_options(cache: &Cache) -> Options {
+    let mut block_opts = BlockBasedOptions::default();
+    block_opts.set_bloom_filter(10.0, false);
+    block_opts.set_block_cache(cache);
+    block_opts.set_cache_index_and_filter_blocks(true);
+    let mut opts = Options::default();
+    opts.set_block_based_table_factory(&block_opts);
+    opts
+}
+
+fn open_database(
+    args: &Args,
+    tree_cf_options: &Options,
+) -> Result<OptimisticTransactionDB, rocksdb::Error> {
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    let cache_size = args.block_cache_size * 1024 * 1024;
+    match args.store_layout {
+        StoreLayout::Default => OptimisticTransactionDB::open_default(&args.db_path),
+        StoreLayout::ColumnFamily => {
+            // the branch and the leaf column families have their own block caches
+            let cfs = vec![
+                ColumnFamilyDescriptor::new(
+                    BRANCH_CF,
+                    column_family_options(&Cache::new_lru_cache(cache_size)),
+                ),
+                ColumnFamilyDescriptor::new(
+                    LEAF_CF,
+                    column_family_options(&Cache::new_lru_cache(cache_size)),
+                ),
+            ];
+            OptimisticTransactionDB::open_cf_descriptors(&opts, &args.db_path, cfs)
+        }
+        StoreLayout::TreeColumnFamily => {
+            // all the column families must be opened, the database does not exist yet if they can't be listed
+            let cfs = DB::list_cf(&opts, &args.db_path)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|name| name.starts_with(TREE_CF_PREFIX))
+                .map(|name| ColumnFamilyDescriptor::new(name, tree_cf_options.clone()))
+                .collect::<Vec<_>>();
+            info!("tree column families num = {}", cfs.len());
+            OptimisticTransactionDB::open_cf_descriptors(&opts, &args.db_path, cfs)
+        }
+    }
+}
+
+// serve the database with the backend of its store layout until the process is stopped
+async fn serve<B: StoreBackend>(
+    rpc_server: RpcServerImpl<B>,
+    listen_addr: &str,
+) -> anyhow::Result<()> {
+    let server = HttpServerBuilder::default()
+        .build(listen_addr.parse::<SocketAddr>()?)
+        .await?;
+    // resume the cleanup of the trees abandoned before a restart
+    rpc_server.spawn_cleanup();
+    let _handle = server.start(rpc_server.into_rpc())?;
+    info!("server started at http://{}", listen_addr);
+    futures::future::pending().await
 }
 
 #[tokio::main]
@@ -25,11 +119,14 @@ async fn main() -> anyhow::Result<()> {
 
     info!("opening database");
 
-    let db = match OptimisticTransactionDB::open_default(args.db_path) {
+    // the trees of the tree column family layout share a block cache
+    let tree_cf_options =
+        column_family_options(&Cache::new_lru_cache(args.block_cache_size * 1024 * 1024));
+    let db = match open_database(&args, &tree_cf_options) {
         Ok(d) => d,
         Err(e) => {
             error!("cannot open database :{}", &e);
-            return Ok(());
+            return Err(e.into());
         }
     };
 
@@ -54,11 +151,42 @@ async fn main() -> anyhow::Result<()> {
     //     }
     // };
 
+    let metadata = match check_metadata(&db, args.branch_node_encoding, args.store_layout) {
+        Ok(m) => m,
+        Err(e) => {
+            error!("cannot serve database :{}", &e);
+            return Err(e);
+        }
+    };
+    info!("database metadata: {:?}", metadata);
+    let version_db_path = Path::new(&args.db_path).join(LEGACY_VERSION_DB_DIR);
+    if version_db_path.exists() {
+        warn!(
+            "the legacy version database {} is not read, run `migrate` to migrate the versions of the trees recorded there",
+            version_db_path.display()
+        );
+    }
+
     info!("opening database success");
-    let server = HttpServerBuilder::default()
-        .build(args.listen_addr.parse::<SocketAddr>()?)
-        .await?;
-    let _handle = server.start(RpcServerImpl::new(db).into_rpc())?;
-    info!("server started at http://{}", args.listen_addr);
-    futures::future::pending().await
+    let rpc_server = RpcServerImpl::new(db)
+        .with_encoding(metadata.branch_node_encoding)
+        .with_tree_cf_options(tree_cf_options)
+        .with_history_window(args.history_window);
+    match metadata.store_layout {
+        StoreLayout::Default => serve(rpc_server, &args.listen_addr).await,
+        StoreLayout::ColumnFamily => {
+            serve(
+                rpc_server.with_backend::<ColumnFamilyBackend>(),
+                &args.listen_addr,
+            )
+            .await
+        }
+        StoreLayout::TreeColumnFamily => {
+            serve(
+                rpc_server.with_backend::<TreeColumnFamilyBackend>(),
+                &args.listen_addr,
+            )
+            .await
+        }
+    }
 }
@@ -5,6 +5,9 @@ const BLAKE2B_KEY: &[u8] = &[];
 const BLAKE2B_LEN: usize = 32;
 const PERSONALIZATION: &[u8] = b"ckb-default-hash";
 
+/// Identifies `Blake2bHasherCustom` in the metadata of a database.
+pub const HASHER_ID: &str = "blake2b-256/ckb-default-hash";
+
 pub struct Blake2bHasherCustom(Blake2b);
 
 impl Default for Blake2bHasherCustom {
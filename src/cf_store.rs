@@ -1,13 +1,20 @@
+use std::collections::BTreeMap;
 use std::marker::PhantomData;
 
-use rocksdb::prelude::*;
+use anyhow::anyhow;
+use rocksdb::{prelude::*, Direction, IteratorMode, OptimisticTransactionDB};
 use sparse_merkle_tree::{
     error::Error,
-    traits::{StoreReadOps, StoreWriteOps, Value},
-    BranchKey, BranchNode, H256,
+    traits::{Hasher, StoreReadOps, StoreWriteOps, Value},
+    BranchKey, BranchNode, SparseMerkleTree, H256,
 };
 
-use crate::serde::{branch_key_to_vec, branch_node_to_vec, slice_to_branch_node};
+use crate::migration::{legacy_leaves, root_branch_names};
+use crate::serde::{
+    branch_key_to_vec, record_to_branch_node, tree_name_to_prefix, BranchNodeEncoding, TREE_KEY_TAG,
+};
+
+const REPAIR_CHUNK_SIZE: usize = 5000;
 
 /// A SMT `Store` implementation backed by a RocksDB database, using different column families to store the branches and the leaves.
 pub struct ColumnFamilyStore<'a, T, W> {
@@ -15,6 +22,8 @@ pub struct ColumnFamilyStore<'a, T, W> {
     inner: &'a T,
     branch_col: &'a ColumnFamily,
     leaf_col: &'a ColumnFamily,
+    // The encoding of the branch nodes written by the store.
+    encoding: BranchNodeEncoding,
     // A generic write options, can be a `WriteOptions` / `()` etc.
     write_options: PhantomData<W>,
 }
@@ -23,11 +32,18 @@ impl<'a, T, W> ColumnFamilyStore<'a, T, W> {
     pub fn new(db: &'a T, branch_col: &'a ColumnFamily, leaf_col: &'a ColumnFamily) -> Self {
         ColumnFamilyStore {
             inner: db,
+            encoding: BranchNodeEncoding::default(),
             write_options: PhantomData,
             branch_col,
             leaf_col,
         }
     }
+
+    /// Write the branch nodes with `encoding`, the branch nodes of both encodings are always readable.
+    pub fn with_encoding(mut self, encoding: BranchNodeEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
 }
 
 impl<'a, V, T, W> StoreReadOps<V> for ColumnFamilyStore<'a, T, W>
@@ -36,10 +52,12 @@ where
     T: GetCF<ReadOptions>,
 {
     fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error> {
+        let key = branch_key_to_vec(branch_key);
         self.inner
-            .get_cf(self.branch_col, &branch_key_to_vec(branch_key))
-            .map(|s| s.map(|v| slice_to_branch_node(&v)))
-            .map_err(|e| Error::Store(e.to_string()))
+            .get_cf(self.branch_col, &key)
+            .map_err(|e| Error::Store(e.to_string()))?
+            .map(|v| record_to_branch_node(&key, &v))
+            .transpose()
     }
 
     fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error> {
@@ -60,14 +78,14 @@ where
             .put_cf(
                 self.branch_col,
                 &branch_key_to_vec(&node_key),
-                &branch_node_to_vec(&branch),
+                &self.encoding.encode(&branch),
             )
             .map_err(|e| Error::Store(e.to_string()))
     }
 
     fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<(), Error> {
         self.inner
-            .put_cf(self.branch_col, leaf_key.as_slice(), leaf)
+            .put_cf(self.leaf_col, leaf_key.as_slice(), leaf)
             .map_err(|e| Error::Store(e.to_string()))
     }
 
@@ -79,38 +97,63 @@ where
 
     fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error> {
         self.inner
-            .delete_cf(self.branch_col, leaf_key.as_slice())
+            .delete_cf(self.leaf_col, leaf_key.as_slice())
             .map_err(|e| Error::Store(e.to_string()))
     }
 }
 
 /// A SMT `Store` implementation backed by a RocksDB database, using different column families to store the branches and the leaves, supports multiple trees.
 pub struct ColumnFamilyStoreMultiTree<'a, T, W> {
-    // A key prefix to distinguish different trees.
-    prefix: &'a [u8],
+    // A key prefix to distinguish different trees, see `tree_name_to_prefix`.
+    prefix: Vec<u8>,
     // The RocksDB database which stores the data, can be a `DB` / `OptimisticTransactionDB` / `Snapshot` etc.
     inner: &'a T,
     branch_col: &'a ColumnFamily,
     leaf_col: &'a ColumnFamily,
+    // The encoding of the branch nodes written by the store.
+    encoding: BranchNodeEncoding,
     // A generic write options, can be a `WriteOptions` / `()` etc.
     write_options: PhantomData<W>,
 }
 
 impl<'a, T, W> ColumnFamilyStoreMultiTree<'a, T, W> {
     pub fn new(
-        prefix: &'a [u8],
+        tree_name: &[u8],
+        db: &'a T,
+        branch_col: &'a ColumnFamily,
+        leaf_col: &'a ColumnFamily,
+    ) -> Self {
+        Self::new_with_raw_prefix(&tree_name_to_prefix(tree_name), db, branch_col, leaf_col)
+    }
+
+    /// Use `prefix` as is to prefix the keys of the tree, it's only used to read the trees written with the legacy
+    /// layout, in which the keys of a tree may collide with the keys of other trees.
+    pub fn new_with_raw_prefix(
+        prefix: &[u8],
         db: &'a T,
         branch_col: &'a ColumnFamily,
         leaf_col: &'a ColumnFamily,
     ) -> Self {
         ColumnFamilyStoreMultiTree {
-            prefix,
+            prefix: prefix.to_vec(),
             inner: db,
+            encoding: BranchNodeEncoding::default(),
             write_options: PhantomData,
             branch_col,
             leaf_col,
         }
     }
+
+    /// The prefix of the keys of all the records of the tree.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Write the branch nodes with `encoding`, the branch nodes of both encodings are always readable.
+    pub fn with_encoding(mut self, encoding: BranchNodeEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
 }
 
 impl<'a, V, T, W> StoreReadOps<V> for ColumnFamilyStoreMultiTree<'a, T, W>
@@ -119,18 +162,20 @@ where
     T: GetCF<ReadOptions>,
 {
     fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error> {
+        let key = [self.prefix.as_slice(), &branch_key_to_vec(branch_key)].concat();
         self.inner
-            .get_cf(
-                self.branch_col,
-                &[self.prefix, &branch_key_to_vec(branch_key)].concat(),
-            )
-            .map(|s| s.map(|v| slice_to_branch_node(&v)))
-            .map_err(|e| Error::Store(e.to_string()))
+            .get_cf(self.branch_col, &key)
+            .map_err(|e| Error::Store(e.to_string()))?
+            .map(|v| record_to_branch_node(&key, &v))
+            .transpose()
     }
 
     fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error> {
         self.inner
-            .get_cf(self.leaf_col, &[self.prefix, leaf_key.as_slice()].concat())
+            .get_cf(
+                self.leaf_col,
+                &[self.prefix.as_slice(), leaf_key.as_slice()].concat(),
+            )
             .map(|s| s.map(|v| v.into()))
             .map_err(|e| Error::Store(e.to_string()))
     }
@@ -145,8 +190,8 @@ where
         self.inner
             .put_cf(
                 self.branch_col,
-                &[self.prefix, &branch_key_to_vec(&node_key)].concat(),
-                &branch_node_to_vec(&branch),
+                &[self.prefix.as_slice(), &branch_key_to_vec(&node_key)].concat(),
+                &self.encoding.encode(&branch),
             )
             .map_err(|e| Error::Store(e.to_string()))
     }
@@ -154,8 +199,8 @@ where
     fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<(), Error> {
         self.inner
             .put_cf(
-                self.branch_col,
-                &[self.prefix, leaf_key.as_slice()].concat(),
+                self.leaf_col,
+                &[self.prefix.as_slice(), leaf_key.as_slice()].concat(),
                 leaf,
             )
             .map_err(|e| Error::Store(e.to_string()))
@@ -165,7 +210,7 @@ where
         self.inner
             .delete_cf(
                 self.branch_col,
-                &[self.prefix, &branch_key_to_vec(node_key)].concat(),
+                &[self.prefix.as_slice(), &branch_key_to_vec(node_key)].concat(),
             )
             .map_err(|e| Error::Store(e.to_string()))
     }
@@ -173,9 +218,176 @@ where
     fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error> {
         self.inner
             .delete_cf(
-                self.branch_col,
-                &[self.prefix, leaf_key.as_slice()].concat(),
+                self.leaf_col,
+                &[self.prefix.as_slice(), leaf_key.as_slice()].concat(),
+            )
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+}
+
+/// A SMT `Store` implementation backed by a RocksDB database, storing a single tree in a column family of its own, the
+/// branches and the leaves are told apart by the length of their keys. Removing the tree is dropping the column family.
+pub struct ColumnFamilyStorePerTree<'a, T, W> {
+    // The RocksDB database which stores the data, can be a `DB` / `OptimisticTransactionDB` / `Snapshot` etc.
+    inner: &'a T,
+    // The column family of the tree, see `tree_column_family_name`.
+    col: &'a ColumnFamily,
+    // The encoding of the branch nodes written by the store.
+    encoding: BranchNodeEncoding,
+    // A generic write options, can be a `WriteOptions` / `()` etc.
+    write_options: PhantomData<W>,
+}
+
+impl<'a, T, W> ColumnFamilyStorePerTree<'a, T, W> {
+    pub fn new(db: &'a T, col: &'a ColumnFamily) -> Self {
+        ColumnFamilyStorePerTree {
+            inner: db,
+            col,
+            encoding: BranchNodeEncoding::default(),
+            write_options: PhantomData,
+        }
+    }
+
+    /// Write the branch nodes with `encoding`, the branch nodes of both encodings are always readable.
+    pub fn with_encoding(mut self, encoding: BranchNodeEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+}
+
+impl<'a, V, T, W> StoreReadOps<V> for ColumnFamilyStorePerTree<'a, T, W>
+where
+    V: Value + AsRef<[u8]> + From<DBVector>,
+    T: GetCF<ReadOptions>,
+{
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error> {
+        let key = branch_key_to_vec(branch_key);
+        self.inner
+            .get_cf(self.col, &key)
+            .map_err(|e| Error::Store(e.to_string()))?
+            .map(|v| record_to_branch_node(&key, &v))
+            .transpose()
+    }
+
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error> {
+        self.inner
+            .get_cf(self.col, leaf_key.as_slice())
+            .map(|s| s.map(|v| v.into()))
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+}
+
+impl<'a, V, T, W> StoreWriteOps<V> for ColumnFamilyStorePerTree<'a, T, W>
+where
+    V: Value + AsRef<[u8]> + From<DBVector>,
+    T: DeleteCF<W> + PutCF<W>,
+{
+    fn insert_branch(&mut self, node_key: BranchKey, branch: BranchNode) -> Result<(), Error> {
+        self.inner
+            .put_cf(
+                self.col,
+                &branch_key_to_vec(&node_key),
+                &self.encoding.encode(&branch),
             )
             .map_err(|e| Error::Store(e.to_string()))
     }
+
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<(), Error> {
+        self.inner
+            .put_cf(self.col, leaf_key.as_slice(), leaf)
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+
+    fn remove_branch(&mut self, node_key: &BranchKey) -> Result<(), Error> {
+        self.inner
+            .delete_cf(self.col, &branch_key_to_vec(node_key))
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error> {
+        self.inner
+            .delete_cf(self.col, leaf_key.as_slice())
+            .map_err(|e| Error::Store(e.to_string()))
+    }
+}
+
+/// Move the leaves written to the branch column family by the older versions of the column family stores, which wrote
+/// leaves to the branch column family but read them from the leaf column family, into the leaf column family. Returns
+/// the number of moved leaves.
+///
+/// The leaves of the trees written with the legacy layout, which used the raw tree name as the key prefix, can not be
+/// told apart from the branches of the trees whose names start with the same bytes, so they are only moved once they
+/// are verified against the root of their tree with a merkle proof, the unverified records are left for the migration
+/// to remove.
+///
+/// A leaf which is also found in the leaf column family was written after the stores were fixed, so the record in the
+/// leaf column family is kept and the stray record is only removed. The records are moved in chunks, each chunk in a
+/// single transaction, so the repair can be resumed if it's interrupted. It should be run before the database is
+/// migrated or updated by the fixed stores, which would not remove the stray records of the leaves they remove.
+pub fn repair_stray_leaves<H, V>(
+    db: &OptimisticTransactionDB,
+    branch_col: &ColumnFamily,
+    leaf_col: &ColumnFamily,
+) -> anyhow::Result<usize>
+where
+    H: Hasher + Default,
+    V: Value + AsRef<[u8]> + From<DBVector>,
+{
+    let snapshot = db.snapshot();
+    let mut stray_leaves: BTreeMap<Box<[u8]>, Box<[u8]>> = snapshot
+        .iterator_cf(branch_col, IteratorMode::Start)?
+        .filter(|(k, _)| is_leaf_key(k))
+        .collect();
+    for name in root_branch_names(snapshot.iterator_cf(branch_col, IteratorMode::Start)?) {
+        // the legacy store read the leaves from the branch column family as well
+        let legacy_smt = SparseMerkleTree::<H, V, _>::new_with_store(ColumnFamilyStoreMultiTree::<
+            _,
+            (),
+        >::new_with_raw_prefix(
+            &name, &snapshot, branch_col, branch_col,
+        ))
+        .map_err(|e| anyhow!("cannot open legacy tree: {}", e))?;
+        let keys = snapshot
+            .iterator_cf(
+                branch_col,
+                IteratorMode::From(name.as_slice(), Direction::Forward),
+            )?
+            .take_while(|(k, _)| k.starts_with(&name))
+            .map(|(k, _)| k);
+        for (leaf_key, value) in legacy_leaves(&legacy_smt, keys, &name)? {
+            stray_leaves.insert(
+                [name.as_slice(), leaf_key.as_slice()].concat().into(),
+                value.as_ref().into(),
+            );
+        }
+    }
+
+    let stray_leaves: Vec<(Box<[u8]>, Box<[u8]>)> = stray_leaves.into_iter().collect();
+    let mut moved = 0;
+    for chunk in stray_leaves.chunks(REPAIR_CHUNK_SIZE) {
+        let tx = db.transaction_default();
+        for (k, v) in chunk {
+            if tx.get_cf(leaf_col, k)?.is_none() {
+                tx.put_cf(leaf_col, k, v)?;
+                moved += 1;
+            }
+            tx.delete_cf(branch_col, k)?;
+        }
+        tx.commit()?;
+    }
+    Ok(moved)
+}
+
+// whether a key of the branch column family is the key of a leaf of `ColumnFamilyStore` or
+// `ColumnFamilyStoreMultiTree`, branch keys are one byte longer than leaf keys
+fn is_leaf_key(key: &[u8]) -> bool {
+    if key.len() == 32 {
+        return true;
+    }
+    if key.len() < 5 || key[0] != TREE_KEY_TAG {
+        return false;
+    }
+    let mut name_len = [0u8; 4];
+    name_len.copy_from_slice(&key[1..5]);
+    key.len() - 5 == u32::from_be_bytes(name_len) as usize + 32
 }
@@ -7,12 +7,16 @@ use sparse_merkle_tree::{
     BranchKey, BranchNode, H256,
 };
 
-use crate::serde::{branch_key_to_vec, branch_node_to_vec, slice_to_branch_node};
+use crate::serde::{
+    branch_key_to_vec, record_to_branch_node, tree_name_to_prefix, BranchNodeEncoding,
+};
 
 /// A SMT `Store` implementation backed by a RocksDB database, using the default column family.
 pub struct DefaultStore<'a, T, W> {
     // The RocksDB database which stores the data, can be a `DB` / `OptimisticTransactionDB` / `Snapshot` etc.
     inner: &'a T,
+    // The encoding of the branch nodes written by the store.
+    encoding: BranchNodeEncoding,
     // A generic write options, can be a `WriteOptions` / `()` etc.
     write_options: PhantomData<W>,
 }
@@ -21,9 +25,16 @@ impl<'a, T, W> DefaultStore<'a, T, W> {
     pub fn new(db: &'a T) -> Self {
         DefaultStore {
             inner: db,
+            encoding: BranchNodeEncoding::default(),
             write_options: PhantomData,
         }
     }
+
+    /// Write the branch nodes with `encoding`, the branch nodes of both encodings are always readable.
+    pub fn with_encoding(mut self, encoding: BranchNodeEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
 }
 
 impl<'a, V, T, W> StoreReadOps<V> for DefaultStore<'a, T, W>
@@ -32,10 +43,12 @@ where
     T: Get<ReadOptions>,
 {
     fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error> {
+        let key = branch_key_to_vec(branch_key);
         self.inner
-            .get(&branch_key_to_vec(branch_key))
-            .map(|s| s.map(|v| slice_to_branch_node(&v)))
-            .map_err(|e| Error::Store(e.to_string()))
+            .get(&key)
+            .map_err(|e| Error::Store(e.to_string()))?
+            .map(|v| record_to_branch_node(&key, &v))
+            .transpose()
     }
 
     fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error> {
@@ -53,7 +66,10 @@ where
 {
     fn insert_branch(&mut self, node_key: BranchKey, branch: BranchNode) -> Result<(), Error> {
         self.inner
-            .put(&branch_key_to_vec(&node_key), &branch_node_to_vec(&branch))
+            .put(
+                &branch_key_to_vec(&node_key),
+                &self.encoding.encode(&branch),
+            )
             .map_err(|e| Error::Store(e.to_string()))
     }
 
@@ -78,22 +94,42 @@ where
 
 /// A SMT `Store` implementation backed by a RocksDB database, using the default column family and supports multiple trees.
 pub struct DefaultStoreMultiTree<'a, T, W> {
-    // A key prefix to distinguish different trees.
-    prefix: &'a [u8],
+    // A key prefix to distinguish different trees, see `tree_name_to_prefix`.
+    prefix: Vec<u8>,
     // The RocksDB database which stores the data, can be a `DB` / `OptimisticTransactionDB` / `Snapshot` etc.
     inner: &'a T,
+    // The encoding of the branch nodes written by the store.
+    encoding: BranchNodeEncoding,
     // A generic write options, can be a `WriteOptions` / `()` etc.
     write_options: PhantomData<W>,
 }
 
 impl<'a, T, W> DefaultStoreMultiTree<'a, T, W> {
-    pub fn new(prefix: &'a [u8], db: &'a T) -> Self {
+    pub fn new(tree_name: &[u8], db: &'a T) -> Self {
+        Self::new_with_raw_prefix(&tree_name_to_prefix(tree_name), db)
+    }
+
+    /// Use `prefix` as is to prefix the keys of the tree, it's only used to read the trees written with the legacy
+    /// layout, in which the keys of a tree may collide with the keys of other trees.
+    pub fn new_with_raw_prefix(prefix: &[u8], db: &'a T) -> Self {
         DefaultStoreMultiTree {
-            prefix,
+            prefix: prefix.to_vec(),
             inner: db,
+            encoding: BranchNodeEncoding::default(),
             write_options: PhantomData,
         }
     }
+
+    /// The prefix of the keys of all the records of the tree.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Write the branch nodes with `encoding`, the branch nodes of both encodings are always readable.
+    pub fn with_encoding(mut self, encoding: BranchNodeEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
 }
 
 impl<'a, V, T, W> StoreReadOps<V> for DefaultStoreMultiTree<'a, T, W>
@@ -102,15 +138,17 @@ where
     T: Get<ReadOptions>,
 {
     fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error> {
+        let key = [self.prefix.as_slice(), &branch_key_to_vec(branch_key)].concat();
         self.inner
-            .get(&[self.prefix, &branch_key_to_vec(branch_key)].concat())
-            .map(|s| s.map(|v| slice_to_branch_node(&v)))
-            .map_err(|e| Error::Store(e.to_string()))
+            .get(&key)
+            .map_err(|e| Error::Store(e.to_string()))?
+            .map(|v| record_to_branch_node(&key, &v))
+            .transpose()
     }
 
     fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error> {
         self.inner
-            .get(&[self.prefix, leaf_key.as_slice()].concat())
+            .get(&[self.prefix.as_slice(), leaf_key.as_slice()].concat())
             .map(|s| s.map(|v| v.into()))
             .map_err(|e| Error::Store(e.to_string()))
     }
@@ -124,27 +162,30 @@ where
     fn insert_branch(&mut self, node_key: BranchKey, branch: BranchNode) -> Result<(), Error> {
         self.inner
             .put(
-                &[self.prefix, &branch_key_to_vec(&node_key)].concat(),
-                &branch_node_to_vec(&branch),
+                &[self.prefix.as_slice(), &branch_key_to_vec(&node_key)].concat(),
+                &self.encoding.encode(&branch),
             )
             .map_err(|e| Error::Store(e.to_string()))
     }
 
     fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<(), Error> {
         self.inner
-            .put(&[self.prefix, leaf_key.as_slice()].concat(), leaf)
+            .put(
+                &[self.prefix.as_slice(), leaf_key.as_slice()].concat(),
+                leaf,
+            )
             .map_err(|e| Error::Store(e.to_string()))
     }
 
     fn remove_branch(&mut self, node_key: &BranchKey) -> Result<(), Error> {
         self.inner
-            .delete(&[self.prefix, &branch_key_to_vec(node_key)].concat())
+            .delete(&[self.prefix.as_slice(), &branch_key_to_vec(node_key)].concat())
             .map_err(|e| Error::Store(e.to_string()))
     }
 
     fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error> {
         self.inner
-            .delete(&[self.prefix, leaf_key.as_slice()].concat())
+            .delete(&[self.prefix.as_slice(), leaf_key.as_slice()].concat())
             .map_err(|e| Error::Store(e.to_string()))
     }
 }
@@ -0,0 +1,59 @@
+use std::collections::HashSet;
+
+#[cfg(feature = "trie")]
+use sparse_merkle_tree::merge::MergeValue;
+use sparse_merkle_tree::{error::Error, traits::StoreReadOps, BranchKey, H256};
+
+use crate::serde::branch_key_to_vec;
+
+/// The keys of the branch nodes and the leaves reachable from the root of the tree in `store`, the branch keys are
+/// serialized by `branch_key_to_vec` and the leaf keys are kept as is, they're told apart by their length.
+///
+/// The tree is walked down from the root branch node, following the non-zero children of each branch node, so the
+/// records which are not reachable from the root, e.g. an orphaned leaf and the branch nodes above it, are never
+/// visited. Any other record of the tree is garbage. With the `trie` feature a child may be a shortcut to a single
+/// leaf, which has no branch nodes below it, so the walk stops there. Any other non-zero child of a branch node of
+/// height `h` is the branch node of height `h - 1`, a missing one is reported as `Error::MissingBranch`.
+pub fn reachable_keys<V, S>(store: &S) -> Result<HashSet<Vec<u8>>, Error>
+where
+    S: StoreReadOps<V>,
+{
+    let mut reachable = HashSet::new();
+    let root_branch_key = BranchKey::new(u8::MAX, H256::zero());
+    let mut pending = match store.get_branch(&root_branch_key)? {
+        Some(branch) => vec![(root_branch_key, branch)],
+        // the tree is empty
+        None => return Ok(reachable),
+    };
+    while let Some((branch_key, branch)) = pending.pop() {
+        reachable.insert(branch_key_to_vec(&branch_key));
+        // the key of the left child has the bit `branch_key.height` cleared, of the right child set
+        let mut right_key = branch_key.node_key;
+        right_key.set_bit(branch_key.height);
+        for (child, node_key) in [
+            (branch.left, branch_key.node_key),
+            (branch.right, right_key),
+        ] {
+            if child.is_zero() {
+                continue;
+            }
+            #[cfg(feature = "trie")]
+            if let MergeValue::ShortCut { key, .. } = child {
+                reachable.insert(key.as_slice().to_vec());
+                continue;
+            }
+            // the children of the branch nodes of height 0 are the leaves
+            if branch_key.height == 0 {
+                reachable.insert(node_key.as_slice().to_vec());
+                continue;
+            }
+            let height = branch_key.height - 1;
+            let child_key = BranchKey::new(height, node_key);
+            match store.get_branch(&child_key)? {
+                Some(child_branch) => pending.push((child_key, child_branch)),
+                None => return Err(Error::MissingBranch(height, node_key)),
+            }
+        }
+    }
+    Ok(reachable)
+}
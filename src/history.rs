@@ -0,0 +1,482 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, Result};
+use rocksdb::{
+    prelude::{Delete, Get, Iterate, Put},
+    DBVector, Direction, IteratorMode, ReadOptions,
+};
+use sparse_merkle_tree::{
+    error::Error,
+    traits::{StoreReadOps, StoreWriteOps, Value},
+    BranchKey, BranchNode, H256,
+};
+
+use crate::serde::{
+    branch_key_to_vec, branch_node_to_vec, record_to_branch_node, tree_name_to_prefix,
+};
+use crate::utils::slice_to_hex_string;
+
+// The history of the trees is recorded under the META key tag, so it's never mistaken for the records of a tree.
+//
+// * version table: `VERSION_KEY_TAG | tree prefix | version` => root | tag
+// * undo records: `UNDO_KEY_TAG | tree prefix | record key | version` => the record before the version, empty if the
+//   record did not exist
+// * journal: `JOURNAL_KEY_TAG | tree prefix | version | record key` => empty, the records written by each version
+// * last version: `LAST_VERSION_KEY_TAG | tree prefix` => the latest version ever recorded, so the versions removed by
+//   `truncate_versions` are never given out again
+//
+// The record key is `LEAF_RECORD | leaf key` or `BRANCH_RECORD | branch_key_to_vec`. The versions are big endian, so
+// the undo record of a record right after a version is found with a single seek, and the records of the oldest versions
+// are pruned from the front of the journal.
+const VERSION_KEY_TAG: &[u8] = b"\x00version";
+const UNDO_KEY_TAG: &[u8] = b"\x00undo";
+const JOURNAL_KEY_TAG: &[u8] = b"\x00journal";
+const LAST_VERSION_KEY_TAG: &[u8] = b"\x00last_version";
+
+const LEAF_RECORD: u8 = 0;
+const BRANCH_RECORD: u8 = 1;
+
+/// A committed version of a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionRecord {
+    pub version: u64,
+    pub root: H256,
+    // the tag given by the caller when the version was committed, e.g. a block number
+    pub tag: Option<u64>,
+}
+
+/// The previous content of the records written to a tree, keyed by the record key, an empty value means the record did
+/// not exist.
+pub type Changes = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn tagged_prefix(tag: &[u8], tree_name: &[u8]) -> Vec<u8> {
+    [tag, tree_name_to_prefix(tree_name).as_slice()].concat()
+}
+
+fn leaf_record_key(leaf_key: &H256) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(33);
+    ret.push(LEAF_RECORD);
+    ret.extend_from_slice(leaf_key.as_slice());
+    ret
+}
+
+fn branch_record_key(branch_key: &BranchKey) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(34);
+    ret.push(BRANCH_RECORD);
+    ret.extend_from_slice(&branch_key_to_vec(branch_key));
+    ret
+}
+
+fn version_from_slice(slice: &[u8]) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    if slice.len() != 8 {
+        return Err(anyhow!("invalid version of {} bytes", slice.len()));
+    }
+    buf.copy_from_slice(slice);
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// The versions of the tree `tree_name` which are retained, oldest first.
+pub fn read_versions<T: Iterate>(db: &T, tree_name: &[u8]) -> Result<Vec<VersionRecord>> {
+    let prefix = tagged_prefix(VERSION_KEY_TAG, tree_name);
+    let mut versions = Vec::new();
+    for (k, v) in db
+        .iterator(IteratorMode::From(&prefix, Direction::Forward))
+        .take_while(|(k, _)| k.starts_with(&prefix))
+    {
+        let version = version_from_slice(&k[prefix.len()..])?;
+        let tag = match v.len() {
+            32 => None,
+            40 => Some(version_from_slice(&v[32..])?),
+            len => {
+                return Err(anyhow!(
+                    "invalid version record of {} bytes, version = {}",
+                    len,
+                    version
+                ))
+            }
+        };
+        let mut root = [0u8; 32];
+        root.copy_from_slice(&v[..32]);
+        versions.push(VersionRecord {
+            version,
+            root: root.into(),
+            tag,
+        });
+    }
+    Ok(versions)
+}
+
+/// Record the commit of `changes` to the tree `tree_name` in `tx` as a new version with `root` and `tag`, and prune the
+/// versions older than the `window` latest ones. Returns the new version.
+pub fn record_version<T, W>(
+    tx: &T,
+    tree_name: &[u8],
+    root: &H256,
+    tag: Option<u64>,
+    changes: &Changes,
+    window: u64,
+) -> Result<u64>
+where
+    T: Iterate + Get<ReadOptions> + Put<W> + Delete<W>,
+{
+    // the databases written before the last version was recorded only have the version table
+    let last_version_key = tagged_prefix(LAST_VERSION_KEY_TAG, tree_name);
+    let last_version = match tx.get(&last_version_key)? {
+        Some(v) => version_from_slice(&v)?,
+        None => 0,
+    };
+    let version = read_versions(tx, tree_name)?
+        .last()
+        .map_or(last_version, |r| r.version.max(last_version))
+        + 1;
+    let version_bytes = version.to_be_bytes();
+    tx.put(&last_version_key, version_bytes)?;
+
+    let mut value = root.as_slice().to_vec();
+    if let Some(tag) = tag {
+        value.extend_from_slice(&tag.to_be_bytes());
+    }
+    let version_prefix = tagged_prefix(VERSION_KEY_TAG, tree_name);
+    tx.put([version_prefix.as_slice(), &version_bytes].concat(), value)?;
+
+    let undo_prefix = tagged_prefix(UNDO_KEY_TAG, tree_name);
+    let journal_prefix = tagged_prefix(JOURNAL_KEY_TAG, tree_name);
+    for (record_key, previous) in changes.iter() {
+        tx.put(
+            [undo_prefix.as_slice(), record_key, &version_bytes].concat(),
+            previous,
+        )?;
+        tx.put(
+            [journal_prefix.as_slice(), &version_bytes, record_key].concat(),
+            b"",
+        )?;
+    }
+
+    if version > window {
+        prune_versions(tx, tree_name, version - window + 1)?;
+    }
+    Ok(version)
+}
+
+/// Delete the versions of the tree `tree_name` older than `oldest`, which is retained. Returns the number of deleted
+/// versions.
+pub fn prune_versions<T, W>(tx: &T, tree_name: &[u8], oldest: u64) -> Result<usize>
+where
+    T: Iterate + Delete<W>,
+{
+    let version_prefix = tagged_prefix(VERSION_KEY_TAG, tree_name);
+    let mut pruned = 0;
+    for record in read_versions(tx, tree_name)? {
+        if record.version >= oldest {
+            break;
+        }
+        tx.delete([version_prefix.as_slice(), &record.version.to_be_bytes()].concat())?;
+        pruned += 1;
+    }
+
+    // the undo records of a version hold the records before it, they are only read at the older versions
+    let undo_prefix = tagged_prefix(UNDO_KEY_TAG, tree_name);
+    let journal_prefix = tagged_prefix(JOURNAL_KEY_TAG, tree_name);
+    for (k, _) in tx
+        .iterator(IteratorMode::From(&journal_prefix, Direction::Forward))
+        .take_while(|(k, _)| k.starts_with(&journal_prefix))
+    {
+        let version = version_from_slice(&k[journal_prefix.len()..journal_prefix.len() + 8])?;
+        if version > oldest {
+            break;
+        }
+        let record_key = &k[journal_prefix.len() + 8..];
+        tx.delete([undo_prefix.as_slice(), record_key, &version.to_be_bytes()].concat())?;
+        tx.delete(&k)?;
+    }
+    Ok(pruned)
+}
+
+/// Delete all the history of the tree `tree_name`. Returns the number of deleted keys.
+pub fn delete_history<T, W>(tx: &T, tree_name: &[u8]) -> Result<usize>
+where
+    T: Iterate + Delete<W>,
+{
+    let mut deleted = 0;
+    for tag in [
+        VERSION_KEY_TAG,
+        UNDO_KEY_TAG,
+        JOURNAL_KEY_TAG,
+        LAST_VERSION_KEY_TAG,
+    ] {
+        let prefix = tagged_prefix(tag, tree_name);
+        for (k, _) in tx
+            .iterator(IteratorMode::From(&prefix, Direction::Forward))
+            .take_while(|(k, _)| k.starts_with(&prefix))
+        {
+            tx.delete(&k)?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// The leaves of the tree `tree_name` at `version` which are written by the later versions, with the zero value for the
+/// leaves which did not exist. Updating the tree with them restores the tree to `version`.
+pub fn rollback_leaves<T, V>(db: &T, tree_name: &[u8], version: u64) -> Result<Vec<(H256, V)>>
+where
+    T: Iterate + Get<ReadOptions>,
+    V: Value + From<DBVector>,
+{
+    let undo_prefix = tagged_prefix(UNDO_KEY_TAG, tree_name);
+    let journal_prefix = tagged_prefix(JOURNAL_KEY_TAG, tree_name);
+    let start = [journal_prefix.as_slice(), &(version + 1).to_be_bytes()].concat();
+    let mut leaf_keys = BTreeSet::new();
+    for (k, _) in db
+        .iterator(IteratorMode::From(&start, Direction::Forward))
+        .take_while(|(k, _)| k.starts_with(&journal_prefix))
+    {
+        let record_key = &k[journal_prefix.len() + 8..];
+        if record_key.len() == 33 && record_key[0] == LEAF_RECORD {
+            let mut leaf_key = [0u8; 32];
+            leaf_key.copy_from_slice(&record_key[1..]);
+            leaf_keys.insert(H256::from(leaf_key));
+        }
+    }
+
+    let mut leaves = Vec::with_capacity(leaf_keys.len());
+    for leaf_key in leaf_keys {
+        let undo = undo_record(db, &undo_prefix, &leaf_record_key(&leaf_key), version)
+            .map_err(|e| anyhow!("{}", e))?;
+        let leaf = match undo {
+            Some(v) if v.is_empty() => V::zero(),
+            Some(v) => V::from(v),
+            None => {
+                return Err(anyhow!(
+                    "the undo record of the leaf {} is missing",
+                    slice_to_hex_string(leaf_key.as_slice())
+                ))
+            }
+        };
+        leaves.push((leaf_key, leaf));
+    }
+    Ok(leaves)
+}
+
+/// Delete the versions of the tree `tree_name` newer than `version`, which becomes the latest version. The numbers of
+/// the deleted versions are not reused by `record_version`. Returns the number of deleted versions.
+pub fn truncate_versions<T, W>(tx: &T, tree_name: &[u8], version: u64) -> Result<usize>
+where
+    T: Iterate + Delete<W>,
+{
+    let version_prefix = tagged_prefix(VERSION_KEY_TAG, tree_name);
+    let mut truncated = 0;
+    for record in read_versions(tx, tree_name)? {
+        if record.version > version {
+            tx.delete([version_prefix.as_slice(), &record.version.to_be_bytes()].concat())?;
+            truncated += 1;
+        }
+    }
+
+    let undo_prefix = tagged_prefix(UNDO_KEY_TAG, tree_name);
+    let journal_prefix = tagged_prefix(JOURNAL_KEY_TAG, tree_name);
+    let start = [journal_prefix.as_slice(), &(version + 1).to_be_bytes()].concat();
+    for (k, _) in tx
+        .iterator(IteratorMode::From(&start, Direction::Forward))
+        .take_while(|(k, _)| k.starts_with(&journal_prefix))
+    {
+        let record_key = &k[journal_prefix.len() + 8..];
+        let record_version = &k[journal_prefix.len()..journal_prefix.len() + 8];
+        tx.delete([undo_prefix.as_slice(), record_key, record_version].concat())?;
+        tx.delete(&k)?;
+    }
+    Ok(truncated)
+}
+
+// The record `record_key` at `version` if it's written by a later version, which is the content recorded by the first
+// version after it. `None` if the record is not written since.
+fn undo_record<T>(
+    db: &T,
+    undo_prefix: &[u8],
+    record_key: &[u8],
+    version: u64,
+) -> Result<Option<DBVector>, Error>
+where
+    T: Iterate + Get<ReadOptions>,
+{
+    let prefix = [undo_prefix, record_key].concat();
+    let start = [prefix.as_slice(), &(version + 1).to_be_bytes()].concat();
+    let undo_key = match db
+        .iterator(IteratorMode::From(&start, Direction::Forward))
+        .next()
+    {
+        Some((k, _)) if k.starts_with(&prefix) && k.len() == prefix.len() + 8 => k,
+        _ => return Ok(None),
+    };
+    match db.get(&undo_key) {
+        Ok(Some(v)) => Ok(Some(v)),
+        Ok(None) => Err(Error::Store(
+            "the undo record is removed while it's read".to_string(),
+        )),
+        Err(e) => Err(Error::Store(e.to_string())),
+    }
+}
+
+/// A `Store` which records the previous content of every record written through it, so the write can be recorded as a
+/// version of the tree with `record_version`. Nothing is recorded if it's disabled.
+pub struct JournalStore<S> {
+    inner: S,
+    enabled: bool,
+    changes: Changes,
+}
+
+impl<S> JournalStore<S> {
+    pub fn new(inner: S, enabled: bool) -> Self {
+        JournalStore {
+            inner,
+            enabled,
+            changes: Changes::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The previous content of the records written so far.
+    pub fn changes(&self) -> &Changes {
+        &self.changes
+    }
+
+    fn record_branch<V>(&mut self, branch_key: &BranchKey) -> Result<(), Error>
+    where
+        S: StoreReadOps<V>,
+    {
+        if !self.enabled {
+            return Ok(());
+        }
+        let record_key = branch_record_key(branch_key);
+        if !self.changes.contains_key(&record_key) {
+            let previous = StoreReadOps::<V>::get_branch(&self.inner, branch_key)?
+                .map(|node| branch_node_to_vec(&node))
+                .unwrap_or_default();
+            self.changes.insert(record_key, previous);
+        }
+        Ok(())
+    }
+
+    fn record_leaf<V>(&mut self, leaf_key: &H256) -> Result<(), Error>
+    where
+        S: StoreReadOps<V>,
+        V: AsRef<[u8]>,
+    {
+        if !self.enabled {
+            return Ok(());
+        }
+        let record_key = leaf_record_key(leaf_key);
+        if !self.changes.contains_key(&record_key) {
+            let previous = self
+                .inner
+                .get_leaf(leaf_key)?
+                .map(|leaf| leaf.as_ref().to_vec())
+                .unwrap_or_default();
+            self.changes.insert(record_key, previous);
+        }
+        Ok(())
+    }
+}
+
+impl<V, S> StoreReadOps<V> for JournalStore<S>
+where
+    S: StoreReadOps<V>,
+{
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error> {
+        self.inner.get_branch(branch_key)
+    }
+
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error> {
+        self.inner.get_leaf(leaf_key)
+    }
+}
+
+impl<V, S> StoreWriteOps<V> for JournalStore<S>
+where
+    V: AsRef<[u8]>,
+    S: StoreReadOps<V> + StoreWriteOps<V>,
+{
+    fn insert_branch(&mut self, node_key: BranchKey, branch: BranchNode) -> Result<(), Error> {
+        self.record_branch::<V>(&node_key)?;
+        self.inner.insert_branch(node_key, branch)
+    }
+
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<(), Error> {
+        self.record_leaf::<V>(&leaf_key)?;
+        self.inner.insert_leaf(leaf_key, leaf)
+    }
+
+    fn remove_branch(&mut self, node_key: &BranchKey) -> Result<(), Error> {
+        self.record_branch::<V>(node_key)?;
+        self.inner.remove_branch(node_key)
+    }
+
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error> {
+        self.record_leaf::<V>(leaf_key)?;
+        self.inner.remove_leaf(leaf_key)
+    }
+}
+
+/// A read only `Store` serving the tree at a retained version from `inner`, the current state of the tree read from
+/// `db`, and the undo records of the later versions. The current state is served if the version is `None`.
+pub struct VersionedStore<'a, T, S> {
+    db: &'a T,
+    undo_prefix: Vec<u8>,
+    version: Option<u64>,
+    inner: S,
+}
+
+impl<'a, T, S> VersionedStore<'a, T, S> {
+    pub fn new(db: &'a T, tree_name: &[u8], version: Option<u64>, inner: S) -> Self {
+        VersionedStore {
+            db,
+            undo_prefix: tagged_prefix(UNDO_KEY_TAG, tree_name),
+            version,
+            inner,
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<'a, T, S> VersionedStore<'a, T, S>
+where
+    T: Iterate + Get<ReadOptions>,
+{
+    fn undo_record(&self, record_key: &[u8]) -> Result<Option<DBVector>, Error> {
+        match self.version {
+            Some(version) => undo_record(self.db, &self.undo_prefix, record_key, version),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'a, V, T, S> StoreReadOps<V> for VersionedStore<'a, T, S>
+where
+    V: Value + From<DBVector>,
+    T: Iterate + Get<ReadOptions>,
+    S: StoreReadOps<V>,
+{
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error> {
+        let record_key = branch_record_key(branch_key);
+        match self.undo_record(&record_key)? {
+            Some(v) if v.is_empty() => Ok(None),
+            Some(v) => record_to_branch_node(&record_key, &v).map(Some),
+            None => self.inner.get_branch(branch_key),
+        }
+    }
+
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error> {
+        match self.undo_record(&leaf_record_key(leaf_key))? {
+            Some(v) if v.is_empty() => Ok(None),
+            Some(v) => Ok(Some(V::from(v))),
+            None => self.inner.get_leaf(leaf_key),
+        }
+    }
+}
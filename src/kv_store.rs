@@ -1,96 +1,179 @@
-use crate::structures::get_db_path;
-use rocksdb::prelude::{Delete, Get, Open, Put};
-use rocksdb::DB;
-use std::sync::Arc;
+use anyhow::{anyhow, Result};
+use rocksdb::prelude::{Delete, Get, Iterate, Put};
+use rocksdb::{Direction, IteratorMode, ReadOptions};
 
-const VERSION_DB_SUFFIX: &str = "/version";
-pub trait KVStore {
-    fn init(file_path: &str) -> Self;
-    fn save(&self, k: &str, v: &str) -> bool;
-    fn find(&self, k: &str) -> Option<String>;
-    fn delete(&self, k: &str) -> bool;
+use crate::serde::tree_name_to_prefix;
+
+// The version of each tree is recorded under the META key tag, the records of a tree are stored under the versioned
+// tree name returned by `get_smt_tree_name`, so bumping the version gives an empty tree without touching its records.
+//
+// * tree versions: `TREE_VERSION_KEY_TAG | tree prefix` => version
+// * abandoned trees: `ABANDONED_KEY_TAG | versioned tree name` => empty, the trees left behind by
+//   `upgrade_smt_tree_version` whose records are not removed yet
+// * tree aliases: `TREE_ALIAS_KEY_TAG | tree prefix` => versioned tree name, the records of a tree swapped by
+//   `swap_smt_trees` are stored under the versioned name of another tree
+const TREE_VERSION_KEY_TAG: &[u8] = b"\x00tree_version";
+const ABANDONED_KEY_TAG: &[u8] = b"\x00abandoned";
+const TREE_ALIAS_KEY_TAG: &[u8] = b"\x00tree_alias";
+
+// Separates the tree name from its version in a versioned tree name, it's never part of a tree name since the names
+// are utf-8 strings.
+const VERSION_SEPARATOR: u8 = 0xff;
+
+fn tree_version_key(smt_name: &str) -> Vec<u8> {
+    [
+        TREE_VERSION_KEY_TAG,
+        tree_name_to_prefix(smt_name.as_bytes()).as_slice(),
+    ]
+    .concat()
 }
-#[derive(Clone)]
-pub struct RocksDB {
-    db: Arc<DB>,
+
+fn tree_alias_key(smt_name: &str) -> Vec<u8> {
+    [
+        TREE_ALIAS_KEY_TAG,
+        tree_name_to_prefix(smt_name.as_bytes()).as_slice(),
+    ]
+    .concat()
 }
-impl KVStore for RocksDB {
-    fn init(file_path: &str) -> Self {
-        RocksDB {
-            db: Arc::new(DB::open_default(file_path).unwrap()),
-        }
+
+/// The name the records of the tree `smt_name` are stored under at `version`, the version 0 is the name itself so the
+/// trees written before they were versioned keep their records.
+pub fn versioned_tree_name(smt_name: &str, version: u64) -> Vec<u8> {
+    if version == 0 {
+        return smt_name.as_bytes().to_vec();
     }
-    fn save(&self, k: &str, v: &str) -> bool {
-        self.db.put(k.as_bytes(), v.as_bytes()).is_ok()
+    let mut ret = smt_name.as_bytes().to_vec();
+    ret.push(VERSION_SEPARATOR);
+    ret.extend_from_slice(&version.to_be_bytes());
+    ret
+}
+
+/// A readable form of a versioned tree name for the logs.
+pub fn tree_name_to_string(tree_name: &[u8]) -> String {
+    match tree_name.iter().position(|b| *b == VERSION_SEPARATOR) {
+        Some(pos) if tree_name.len() == pos + 9 => {
+            let mut version = [0u8; 8];
+            version.copy_from_slice(&tree_name[pos + 1..]);
+            format!(
+                "{}#{}",
+                String::from_utf8_lossy(&tree_name[..pos]),
+                u64::from_be_bytes(version)
+            )
+        }
+        _ => String::from_utf8_lossy(tree_name).to_string(),
     }
-    fn find(&self, k: &str) -> Option<String> {
-        match self.db.get(k.as_bytes()) {
-            Ok(Some(v)) => {
-                let result = String::from_utf8(v.to_owned()).unwrap();
-                println!("Finding '{}' returns '{}'", k, result);
-                Some(result)
-            }
-            Ok(None) => {
-                println!("Finding '{}' returns None", k);
-                None
-            }
-            Err(e) => {
-                println!("Error retrieving value for {}: {}", k, e);
-                None
+}
+
+/// The current version of the tree `smt_name`, 0 if it's never upgraded.
+pub fn get_smt_tree_version<T: Get<ReadOptions>>(db: &T, smt_name: &str) -> Result<u64> {
+    match db.get(tree_version_key(smt_name))? {
+        Some(v) => {
+            if v.len() != 8 {
+                return Err(anyhow!(
+                    "invalid version of smt {} of {} bytes",
+                    smt_name,
+                    v.len()
+                ));
             }
+            let mut version = [0u8; 8];
+            version.copy_from_slice(&v);
+            Ok(u64::from_be_bytes(version))
         }
+        None => Ok(0),
     }
-    fn delete(&self, k: &str) -> bool {
-        self.db.delete(k.as_bytes()).is_ok()
+}
+
+/// The name the records of the tree `smt_name` are currently stored under, its alias if it's swapped with another
+/// tree, or its versioned name otherwise.
+pub fn get_smt_tree_name<T: Get<ReadOptions>>(db: &T, smt_name: &str) -> Result<Vec<u8>> {
+    match db.get(tree_alias_key(smt_name))? {
+        Some(tree_name) => Ok(tree_name.to_vec()),
+        None => Ok(versioned_tree_name(
+            smt_name,
+            get_smt_tree_version(db, smt_name)?,
+        )),
     }
 }
-fn get_version_db_path() -> String {
-    let mut db_path = get_db_path();
-    db_path.push_str(VERSION_DB_SUFFIX);
-    db_path
+
+/// Bump the version of the tree `smt_name` in `tx`, the tree is empty at the new version and the records it's
+/// currently stored under are recorded as abandoned. Returns the new version.
+///
+/// The versions of a tree only grow, so the new versioned name is never the alias of another tree.
+pub fn upgrade_smt_tree_version<T, W>(tx: &T, smt_name: &str) -> Result<u64>
+where
+    T: Get<ReadOptions> + Put<W> + Delete<W>,
+{
+    let tree_name = get_smt_tree_name(tx, smt_name)?;
+    let new_version = get_smt_tree_version(tx, smt_name)? + 1;
+    tx.put(tree_version_key(smt_name), new_version.to_be_bytes())?;
+    tx.delete(tree_alias_key(smt_name))?;
+    tx.put([ABANDONED_KEY_TAG, tree_name.as_slice()].concat(), b"")?;
+    Ok(new_version)
 }
-pub fn get_smt_tree_name(smt_name: &str) -> String {
-    //init db
-    let db: RocksDB = KVStore::init(get_version_db_path().as_str());
-    //get smt_name version
-    match db.find(smt_name) {
-        Some(v) => {
-            //if existed then get
-            format!("{}_{}", smt_name, v)
-        }
-        None => {
-            //if none then insert
-            let version = "0";
-            db.save(smt_name, version);
-            format!("{}_{}", smt_name, version)
-        }
+
+/// Record in `tx` that the tree `smt_name` is at `version` with its records stored under `tree_name`, as recorded by
+/// the legacy version database, see `migrate_legacy_tree_versions`. Returns false if it's already recorded, and fails
+/// if the tree is versioned otherwise.
+pub fn import_smt_tree_version<T, W>(
+    tx: &T,
+    smt_name: &str,
+    version: u64,
+    tree_name: &[u8],
+) -> Result<bool>
+where
+    T: Get<ReadOptions> + Put<W>,
+{
+    if get_smt_tree_name(tx, smt_name)? == tree_name
+        && get_smt_tree_version(tx, smt_name)? == version
+    {
+        return Ok(false);
     }
+    if tx.get(tree_version_key(smt_name))?.is_some() || tx.get(tree_alias_key(smt_name))?.is_some()
+    {
+        return Err(anyhow!(
+            "smt {} is already versioned, its legacy version {} can not be imported",
+            smt_name,
+            version
+        ));
+    }
+    tx.put(tree_version_key(smt_name), version.to_be_bytes())?;
+    // the legacy name is never the versioned name of the tree, so it's kept as the alias
+    tx.put(tree_alias_key(smt_name), tree_name)?;
+    Ok(true)
 }
 
-pub fn upgrade_smt_tree_version(smt_name: &str) -> bool {
-    //init db
-    let db: RocksDB = KVStore::init(get_version_db_path().as_str());
-    //get smt_name version
-    match db.find(smt_name) {
-        Some(v) => {
-            //if existed then get
-            match v.parse::<usize>() {
-                Ok(old_version) => {
-                    let new_version = old_version + 1;
-                    let new_version_str = new_version.to_string();
-                    db.save(smt_name, new_version_str.as_str());
-                    true
-                }
-                Err(e) => {
-                    println!("cannot parse string to number : {}", e);
-                    false
-                }
-            }
-        }
-        None => {
-            //if none then insert
-            println!("cannot find smt tree named :{}", smt_name);
-            false
+/// Swap the records of the trees `smt_name1` and `smt_name2` in `tx` by swapping the names they are stored under, no
+/// record is moved. Returns the new names of both trees.
+pub fn swap_smt_trees<T, W>(tx: &T, smt_name1: &str, smt_name2: &str) -> Result<(Vec<u8>, Vec<u8>)>
+where
+    T: Get<ReadOptions> + Put<W> + Delete<W>,
+{
+    if smt_name1 == smt_name2 {
+        return Err(anyhow!("cannot swap smt {} with itself", smt_name1));
+    }
+    let tree_name1 = get_smt_tree_name(tx, smt_name2)?;
+    let tree_name2 = get_smt_tree_name(tx, smt_name1)?;
+    for (smt_name, tree_name) in [(smt_name1, &tree_name1), (smt_name2, &tree_name2)] {
+        // an alias is only kept while a tree is not stored under its own versioned name
+        if *tree_name == versioned_tree_name(smt_name, get_smt_tree_version(tx, smt_name)?) {
+            tx.delete(tree_alias_key(smt_name))?;
+        } else {
+            tx.put(tree_alias_key(smt_name), tree_name)?;
         }
     }
+    Ok((tree_name1, tree_name2))
+}
+
+/// The versioned names of the abandoned trees whose records are not removed yet.
+pub fn get_abandoned_trees<T: Iterate>(db: &T) -> Vec<Vec<u8>> {
+    db.iterator(IteratorMode::From(ABANDONED_KEY_TAG, Direction::Forward))
+        .take_while(|(k, _)| k.starts_with(ABANDONED_KEY_TAG))
+        .map(|(k, _)| k[ABANDONED_KEY_TAG.len()..].to_vec())
+        .collect()
+}
+
+/// Forget the abandoned tree `tree_name` once its records are removed.
+pub fn forget_abandoned_tree<T: Delete<W>, W>(tx: &T, tree_name: &[u8]) -> Result<()> {
+    tx.delete([ABANDONED_KEY_TAG, tree_name].concat())?;
+    Ok(())
 }
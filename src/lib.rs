@@ -1,10 +1,15 @@
 pub mod blake2b;
 pub mod cf_store;
 pub mod default_store;
+pub mod gc;
+pub mod history;
 pub mod kv_store;
+pub mod migration;
 pub mod rpc_server;
+pub mod schema;
 pub mod serde;
 pub mod structures;
 #[cfg(test)]
 mod tests;
+pub mod tree_store;
 pub mod utils;
@@ -0,0 +1,426 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use rocksdb::{
+    prelude::{Delete, DeleteCF, Iterate, IterateCF, Open},
+    ColumnFamily, DBVector, Direction, IteratorMode, OptimisticTransactionDB, ReadOnlyDB,
+};
+use sparse_merkle_tree::{
+    traits::{Hasher, StoreReadOps, StoreWriteOps, Value},
+    SparseMerkleTree, H256,
+};
+
+use crate::cf_store::ColumnFamilyStoreMultiTree;
+use crate::default_store::DefaultStoreMultiTree;
+use crate::kv_store::import_smt_tree_version;
+use crate::schema::{read_metadata, write_metadata, Metadata, META_KEY_TAG};
+use crate::serde::{BranchNodeEncoding, TREE_KEY_TAG};
+use crate::tree_store::StoreLayout;
+use crate::utils::slice_to_hex_string;
+
+const PURGE_CHUNK_SIZE: usize = 5000;
+
+/// The directory of the legacy version database under the path of a database, the legacy `get_smt_tree_name` recorded
+/// the versions of the trees there.
+pub const LEGACY_VERSION_DB_DIR: &str = "version";
+
+/// Migrate a database written with the legacy layout to the current schema version: every legacy tree is rewritten
+/// with `migrate_legacy_tree`, and the metadata is only recorded once all of them are migrated. If `purge` is
+/// set the legacy records are removed afterwards. Returns the names and roots of the migrated trees.
+pub fn migrate_legacy_database<H, V>(
+    db: &OptimisticTransactionDB,
+    purge: bool,
+) -> Result<Vec<(Vec<u8>, H256)>>
+where
+    H: Hasher + Default,
+    V: Value + AsRef<[u8]> + From<DBVector>,
+{
+    migrate_database::<H, V>(db, None, purge)
+}
+
+/// Migrate a database written with the legacy layout of `ColumnFamilyStoreMultiTree`, whose trees are stored in
+/// `branch_col` and `leaf_col`, to the current schema version, see `migrate_legacy_database`. The metadata records the
+/// column family layout.
+///
+/// The legacy leaves must be in `leaf_col`, the older versions of the store wrote them to `branch_col`, so
+/// `repair_stray_leaves` should be run first.
+pub fn migrate_legacy_cf_database<H, V>(
+    db: &OptimisticTransactionDB,
+    branch_col: &ColumnFamily,
+    leaf_col: &ColumnFamily,
+    purge: bool,
+) -> Result<Vec<(Vec<u8>, H256)>>
+where
+    H: Hasher + Default,
+    V: Value + AsRef<[u8]> + From<DBVector>,
+{
+    migrate_database::<H, V>(db, Some((branch_col, leaf_col)), purge)
+}
+
+// migrate the trees of the default layout if `cols` is `None`, or of the column family layout stored in the branch and
+// the leaf column families `cols`
+fn migrate_database<H, V>(
+    db: &OptimisticTransactionDB,
+    cols: Option<(&ColumnFamily, &ColumnFamily)>,
+    purge: bool,
+) -> Result<Vec<(Vec<u8>, H256)>>
+where
+    H: Hasher + Default,
+    V: Value + AsRef<[u8]> + From<DBVector>,
+{
+    if let Some(metadata) = read_metadata(db)? {
+        metadata.check_compatible()?;
+        info!("the database is already migrated");
+        return Ok(Vec::new());
+    }
+
+    let names = match cols {
+        None => legacy_tree_names(db),
+        Some((branch_col, _)) => legacy_cf_tree_names(db, branch_col)?,
+    };
+    info!("legacy trees num = {}", names.len());
+
+    let mut migrated = Vec::with_capacity(names.len());
+    for name in names {
+        let root = match cols {
+            None => migrate_legacy_tree::<H, V>(db, &name)?,
+            Some((branch_col, leaf_col)) => {
+                migrate_legacy_cf_tree::<H, V>(db, &name, branch_col, leaf_col)?
+            }
+        };
+        // read the root back from the committed records
+        let snapshot = db.snapshot();
+        let migrated_root = match cols {
+            None => *SparseMerkleTree::<H, V, _>::new_with_store(
+                DefaultStoreMultiTree::<_, ()>::new(&name, &snapshot),
+            )
+            .map_err(|e| anyhow!("cannot open migrated tree: {}", e))?
+            .root(),
+            Some((branch_col, leaf_col)) => *SparseMerkleTree::<H, V, _>::new_with_store(
+                ColumnFamilyStoreMultiTree::<_, ()>::new(&name, &snapshot, branch_col, leaf_col),
+            )
+            .map_err(|e| anyhow!("cannot open migrated tree: {}", e))?
+            .root(),
+        };
+        if migrated_root != root {
+            return Err(anyhow!(
+                "root mismatch after commit, tree = {}, legacy root = {}, migrated root = {}",
+                String::from_utf8_lossy(&name),
+                slice_to_hex_string(root.as_slice()),
+                slice_to_hex_string(migrated_root.as_slice())
+            ));
+        }
+        migrated.push((name, root));
+    }
+
+    // the migrated trees are written with the default branch node encoding
+    let layout = match cols {
+        None => StoreLayout::Default,
+        Some(_) => StoreLayout::ColumnFamily,
+    };
+    write_metadata(db, &Metadata::new(BranchNodeEncoding::default(), layout))?;
+    if purge {
+        let removed = match cols {
+            None => remove_legacy_records(db)?,
+            Some((branch_col, leaf_col)) => remove_legacy_cf_records(db, branch_col, leaf_col)?,
+        };
+        info!("legacy records removed num = {}", removed);
+    }
+    Ok(migrated)
+}
+
+/// Migrate the versions of the trees recorded in the legacy version database at `version_db_path` into `db`, nothing is
+/// done if it does not exist. Each entry maps a tree name to its version in decimal, the legacy version `v` of the tree
+/// `name` stored its records under the tree name `name_v`, so the tree is recorded at the version `v` with `name_v` as
+/// its alias and keeps its records. All the versions are migrated in a single transaction, the legacy version database
+/// is left untouched. Returns the names and versions of the migrated trees.
+pub fn migrate_legacy_tree_versions(
+    db: &OptimisticTransactionDB,
+    version_db_path: &Path,
+) -> Result<Vec<(String, u64)>> {
+    if !version_db_path.exists() {
+        return Ok(Vec::new());
+    }
+    let version_db = ReadOnlyDB::open_default(version_db_path)?;
+    let tx = db.transaction_default();
+    let mut migrated = Vec::new();
+    for (k, v) in version_db.iterator(IteratorMode::Start) {
+        let smt_name = String::from_utf8(k.to_vec())
+            .map_err(|_| anyhow!("invalid legacy tree name {}", slice_to_hex_string(&k)))?;
+        let version = std::str::from_utf8(&v)
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "invalid legacy version of smt {}, {}",
+                    smt_name,
+                    slice_to_hex_string(&v)
+                )
+            })?;
+        let tree_name = format!("{}_{}", smt_name, version);
+        if import_smt_tree_version(&tx, &smt_name, version, tree_name.as_bytes())? {
+            info!(
+                "migrated version of smt {}, version = {}, tree name = {}",
+                smt_name, version, tree_name
+            );
+            migrated.push((smt_name, version));
+        }
+    }
+    tx.commit()?;
+    Ok(migrated)
+}
+
+/// Find the names of the trees written with the legacy layout.
+///
+/// Every non-empty tree has a root branch record keyed by the tree name followed by the root `BranchKey`, i.e. a zero
+/// node key and height 255. The names found this way are only candidates, `migrate_legacy_tree` verifies them.
+pub fn legacy_tree_names(db: &OptimisticTransactionDB) -> Vec<Vec<u8>> {
+    let snapshot = db.snapshot();
+    root_branch_names(snapshot.iterator(IteratorMode::Start))
+}
+
+/// Find the names of the trees written with the legacy layout of `ColumnFamilyStoreMultiTree` from their root branch
+/// records in `branch_col`, see `legacy_tree_names`.
+pub fn legacy_cf_tree_names(
+    db: &OptimisticTransactionDB,
+    branch_col: &ColumnFamily,
+) -> Result<Vec<Vec<u8>>> {
+    let snapshot = db.snapshot();
+    Ok(root_branch_names(
+        snapshot.iterator_cf(branch_col, IteratorMode::Start)?,
+    ))
+}
+
+// the names of the legacy trees whose root branch records are in `records`
+pub(crate) fn root_branch_names(
+    records: impl Iterator<Item = (Box<[u8]>, Box<[u8]>)>,
+) -> Vec<Vec<u8>> {
+    let mut root_branch_key = [0u8; 33];
+    root_branch_key[32] = u8::MAX;
+    records
+        .filter(|(k, _)| k.len() >= 33 && k.ends_with(&root_branch_key) && is_legacy_key(k))
+        .map(|(k, _)| k[..k.len() - 33].to_vec())
+        .collect()
+}
+
+/// Remove every record that is neither a record of the current layout nor a record describing the database, returns
+/// the number of removed records.
+pub fn remove_legacy_records(db: &OptimisticTransactionDB) -> Result<usize> {
+    remove_legacy_keys(db, None)
+}
+
+/// Remove every record of `branch_col` and `leaf_col` that is not a record of the current layout, returns the number
+/// of removed records.
+pub fn remove_legacy_cf_records(
+    db: &OptimisticTransactionDB,
+    branch_col: &ColumnFamily,
+    leaf_col: &ColumnFamily,
+) -> Result<usize> {
+    Ok(remove_legacy_keys(db, Some(branch_col))? + remove_legacy_keys(db, Some(leaf_col))?)
+}
+
+// remove the legacy records of the column family `col`, `None` is the default column family
+fn remove_legacy_keys(db: &OptimisticTransactionDB, col: Option<&ColumnFamily>) -> Result<usize> {
+    let snapshot = db.snapshot();
+    let keys: Vec<Box<[u8]>> = match col {
+        None => snapshot
+            .iterator(IteratorMode::Start)
+            .map(|(k, _)| k)
+            .filter(|k| is_legacy_key(k))
+            .collect(),
+        Some(col) => snapshot
+            .iterator_cf(col, IteratorMode::Start)?
+            .map(|(k, _)| k)
+            .filter(|k| is_legacy_key(k))
+            .collect(),
+    };
+    for chunk in keys.chunks(PURGE_CHUNK_SIZE) {
+        let tx = db.transaction_default();
+        for k in chunk {
+            match col {
+                None => tx.delete(k)?,
+                Some(col) => tx.delete_cf(col, k)?,
+            }
+        }
+        tx.commit()?;
+    }
+    Ok(keys.len())
+}
+
+// whether a key can not be written by the current layout, the keys of the current layout start with a tag byte and the
+// keys of tree records hold the length of the tree name
+fn is_legacy_key(key: &[u8]) -> bool {
+    // the records of a legacy tree with an empty name are bare leaf and branch keys, no key of the current layout is
+    // that short
+    if key.len() == 32 || key.len() == 33 {
+        return true;
+    }
+    match key.first() {
+        Some(&META_KEY_TAG) => false,
+        Some(&TREE_KEY_TAG) if key.len() >= 5 => {
+            let mut name_len = [0u8; 4];
+            name_len.copy_from_slice(&key[1..5]);
+            let records_len = key.len() - 5;
+            let name_len = u32::from_be_bytes(name_len) as usize;
+            // a leaf key or a branch key follows the name
+            !(records_len == name_len + 32 || records_len == name_len + 33)
+        }
+        _ => true,
+    }
+}
+
+/// Rewrite the tree `name` written with the legacy layout of `DefaultStoreMultiTree`, which used the raw tree name as
+/// the key prefix, into the current layout, returns the root of the tree.
+///
+/// In the legacy layout the records of a tree can not be told apart from the records of the trees whose names start
+/// with the same bytes, so each leaf found under the legacy prefix is verified against the legacy root with a merkle
+/// proof before it's migrated, and the migrated tree must have the same root as the legacy tree. The tree is written in
+/// a single transaction, the legacy records are left as is.
+pub fn migrate_legacy_tree<H, V>(db: &OptimisticTransactionDB, name: &[u8]) -> Result<H256>
+where
+    H: Hasher + Default,
+    V: Value + AsRef<[u8]> + From<DBVector>,
+{
+    let snapshot = db.snapshot();
+    let legacy_store = DefaultStoreMultiTree::<_, ()>::new_with_raw_prefix(name, &snapshot);
+    let legacy_smt = SparseMerkleTree::<H, V, _>::new_with_store(legacy_store)
+        .map_err(|e| anyhow!("cannot open legacy tree: {}", e))?;
+    let keys = snapshot
+        .iterator(IteratorMode::From(name, Direction::Forward))
+        .take_while(|(k, _)| k.starts_with(name))
+        .map(|(k, _)| k);
+
+    let tx = db.transaction_default();
+    let mut smt =
+        SparseMerkleTree::<H, V, _>::new_with_store(DefaultStoreMultiTree::new(name, &tx))
+            .map_err(|e| anyhow!("cannot open tree: {}", e))?;
+    let root = rewrite_legacy_tree(name, &legacy_smt, keys, &mut smt)?;
+    tx.commit()
+        .map_err(|e| anyhow!("database commit failed: {}", e))?;
+
+    info!("migrate legacy tree {} end", String::from_utf8_lossy(name));
+    Ok(root)
+}
+
+/// Rewrite the tree `name` written with the legacy layout of `ColumnFamilyStoreMultiTree`, which used the raw tree name
+/// as the key prefix in `branch_col` and `leaf_col`, into the current layout, see `migrate_legacy_tree`.
+pub fn migrate_legacy_cf_tree<H, V>(
+    db: &OptimisticTransactionDB,
+    name: &[u8],
+    branch_col: &ColumnFamily,
+    leaf_col: &ColumnFamily,
+) -> Result<H256>
+where
+    H: Hasher + Default,
+    V: Value + AsRef<[u8]> + From<DBVector>,
+{
+    let snapshot = db.snapshot();
+    let legacy_store = ColumnFamilyStoreMultiTree::<_, ()>::new_with_raw_prefix(
+        name, &snapshot, branch_col, leaf_col,
+    );
+    let legacy_smt = SparseMerkleTree::<H, V, _>::new_with_store(legacy_store)
+        .map_err(|e| anyhow!("cannot open legacy tree: {}", e))?;
+    let keys = snapshot
+        .iterator_cf(leaf_col, IteratorMode::From(name, Direction::Forward))?
+        .take_while(|(k, _)| k.starts_with(name))
+        .map(|(k, _)| k);
+
+    let tx = db.transaction_default();
+    let mut smt = SparseMerkleTree::<H, V, _>::new_with_store(ColumnFamilyStoreMultiTree::new(
+        name, &tx, branch_col, leaf_col,
+    ))
+    .map_err(|e| anyhow!("cannot open tree: {}", e))?;
+    let root = rewrite_legacy_tree(name, &legacy_smt, keys, &mut smt)?;
+    tx.commit()
+        .map_err(|e| anyhow!("database commit failed: {}", e))?;
+
+    info!("migrate legacy tree {} end", String::from_utf8_lossy(name));
+    Ok(root)
+}
+
+// write the verified leaves of the legacy tree `legacy_smt` among the records `keys` found under its prefix to the empty
+// tree `smt`, returns the root of both trees
+fn rewrite_legacy_tree<H, V, S, D>(
+    name: &[u8],
+    legacy_smt: &SparseMerkleTree<H, V, S>,
+    keys: impl Iterator<Item = Box<[u8]>>,
+    smt: &mut SparseMerkleTree<H, V, D>,
+) -> Result<H256>
+where
+    H: Hasher + Default,
+    V: Value,
+    S: StoreReadOps<V>,
+    D: StoreReadOps<V> + StoreWriteOps<V>,
+{
+    let root = *legacy_smt.root();
+    info!(
+        "migrate legacy tree {} start, root = {}",
+        String::from_utf8_lossy(name),
+        slice_to_hex_string(root.as_slice())
+    );
+
+    let leaves = legacy_leaves(legacy_smt, keys, name)?;
+    info!("verified legacy leaves num = {}", leaves.len());
+
+    if smt.root() == &root {
+        info!("tree is already migrated");
+        return Ok(root);
+    }
+    if !smt.root().is_zero() {
+        return Err(anyhow!(
+            "tree already exists in the current layout with a different root {}",
+            slice_to_hex_string(smt.root().as_slice())
+        ));
+    }
+    smt.update_all(leaves)
+        .map_err(|e| anyhow!("cannot write tree: {}", e))?;
+    if smt.root() != &root {
+        return Err(anyhow!(
+            "root mismatch after migration, legacy root = {}, migrated root = {}",
+            slice_to_hex_string(root.as_slice()),
+            slice_to_hex_string(smt.root().as_slice())
+        ));
+    }
+    Ok(root)
+}
+
+// collect the leaves of a legacy tree among the records `keys` found under its prefix, skipping the records of other
+// trees that happen to share the prefix
+pub(crate) fn legacy_leaves<H, V, S>(
+    legacy_smt: &SparseMerkleTree<H, V, S>,
+    keys: impl Iterator<Item = Box<[u8]>>,
+    name: &[u8],
+) -> Result<Vec<(H256, V)>>
+where
+    H: Hasher + Default,
+    V: Value,
+    S: StoreReadOps<V>,
+{
+    let root = *legacy_smt.root();
+    let leaf_key_len = name.len() + 32;
+    let mut leaves = Vec::new();
+    for k in keys.filter(|k| k.len() == leaf_key_len) {
+        let leaf_key: [u8; 32] = k[name.len()..].try_into()?;
+        let leaf_key: H256 = leaf_key.into();
+        let value = match StoreReadOps::<V>::get_leaf(legacy_smt.store(), &leaf_key) {
+            Ok(Some(v)) => v,
+            Ok(None) => continue,
+            Err(e) => return Err(anyhow!("cannot read legacy leaf: {}", e)),
+        };
+        let verified = legacy_smt
+            .merkle_proof(vec![leaf_key])
+            .and_then(|p| p.compile(vec![leaf_key]))
+            .and_then(|p| p.verify::<H>(&root, vec![(leaf_key, value.to_h256())]))
+            .unwrap_or(false);
+        if verified {
+            leaves.push((leaf_key, value));
+        } else {
+            warn!(
+                "skip record of another tree, key = {}",
+                slice_to_hex_string(&k)
+            );
+        }
+    }
+    Ok(leaves)
+}
@@ -1,36 +1,696 @@
 use super::{
-    default_store::DefaultStoreMultiTree,
+    blake2b::Blake2bHasherCustom,
+    gc::reachable_keys,
+    history::{self, JournalStore, VersionRecord, VersionedStore},
+    kv_store::{
+        forget_abandoned_tree, get_abandoned_trees, get_smt_tree_name, swap_smt_trees,
+        tree_name_to_string, upgrade_smt_tree_version,
+    },
+    serde::{branch_key_to_vec, BranchNodeEncoding},
     structures::{
-        DefaultStoreMultiSMT, MemoryStoreSMT, Opt, Pair, Response, ResponseSequence, SmtKey,
-        SmtProof, SmtRoot, SmtValue,
+        JournalStoreSMT, MemoryStoreSMT, Opt, Pair, Response, ResponseCopy, ResponseGc,
+        ResponseNonMembershipProof, ResponseProof, ResponseRollback, ResponseSequence,
+        SequenceStep, SmtKey, SmtLeaf, SmtProof, SmtRoot, SmtValue, SmtVersion, SmtVersionQuery,
+        TreeStoreSMT, VersionedStoreSMT,
     },
+    tree_store::{tree_column_family_name, DefaultBackend, StoreBackend, StoreLayout, TreeStore},
     utils::slice_to_hex_string,
 };
 use crate::utils::get_empty_compiled_proof;
 use jsonrpsee::{
     core::{async_trait, Error},
     proc_macros::rpc,
+    types::error::{CallError, ErrorObject},
 };
 
 use log::{debug, error, info, warn};
 use rayon::prelude::*;
-use rocksdb::{prelude::Iterate, OptimisticTransaction, OptimisticTransactionDB};
+use rocksdb::{
+    ops::GetPropertyCF,
+    prelude::{
+        CreateCF, Delete, DeleteCF, DropCF, Get, GetCF, GetColumnFamilys, Iterate, IterateCF, Put,
+        PutCF,
+    },
+    ColumnFamily, OptimisticTransaction, OptimisticTransactionDB, OptimisticTransactionOptions,
+    Options, ReadOptions, WriteOptions,
+};
 use rocksdb::{Direction, IteratorMode};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use sparse_merkle_tree::{traits::Value, H256};
-use std::collections::HashMap;
+use sparse_merkle_tree::{
+    error::Error as SmtError,
+    traits::{StoreReadOps, Value},
+    BranchKey, CompiledMerkleProof, SparseMerkleTree, H256,
+};
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
 
 const CHUNK_SIZE: usize = 5000;
 const MAX_DISPLAY_NUMS: usize = 5;
 
-pub struct RpcServerImpl {
-    db: OptimisticTransactionDB,
+/// The error code returned when the root of the tree is not the `expected_root` of an update.
+pub const ROOT_MISMATCH_ERROR_CODE: i32 = -32010;
+
+/// The error code returned when the records of a tree do not produce the root they're expected to, e.g. the root of a
+/// tree after a rollback is not the root recorded for the version, so the stored data is inconsistent.
+pub const INTEGRITY_ERROR_CODE: i32 = -32011;
+
+/// The RPC server, it serves the trees of the store layout of the backend `B`, see `StoreBackend`.
+#[derive(Clone)]
+pub struct RpcServerImpl<B = DefaultBackend> {
+    // column families can only be created and dropped with exclusive access to the database, and so is the version of a
+    // tree changed, every other access only takes the read lock
+    db: Arc<RwLock<OptimisticTransactionDB>>,
+    // the encoding of the branch nodes written to the database, see `Metadata`
+    encoding: BranchNodeEncoding,
+    // the options of the column families created for the trees of the tree column family layout
+    tree_cf_options: Arc<Options>,
+    // held by the background cleanup of the abandoned trees, so only one runs at a time
+    cleanup_lock: Arc<Mutex<()>>,
+    // the number of versions of each tree retained, the history is disabled if it's 0
+    history_window: u64,
+    // the backend of the layout of the trees in the database, see `Metadata`
+    backend: PhantomData<B>,
 }
 
 impl RpcServerImpl {
     pub fn new(db: OptimisticTransactionDB) -> Self {
-        Self { db }
+        Self {
+            db: Arc::new(RwLock::new(db)),
+            encoding: BranchNodeEncoding::default(),
+            tree_cf_options: Arc::new(Options::default()),
+            cleanup_lock: Arc::new(Mutex::new(())),
+            history_window: 0,
+            backend: PhantomData,
+        }
+    }
+}
+
+impl<B: StoreBackend> RpcServerImpl<B> {
+    /// Write the branch nodes with `encoding`, it should be the encoding recorded in the metadata of the database.
+    pub fn with_encoding(mut self, encoding: BranchNodeEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Serve the trees with the backend `C`, its layout should be the layout recorded in the metadata of the database,
+    /// and the database must be opened with the column families it needs, see `StoreBackend`.
+    pub fn with_backend<C: StoreBackend>(self) -> RpcServerImpl<C> {
+        RpcServerImpl {
+            db: self.db,
+            encoding: self.encoding,
+            tree_cf_options: self.tree_cf_options,
+            cleanup_lock: self.cleanup_lock,
+            history_window: self.history_window,
+            backend: PhantomData,
+        }
+    }
+
+    /// Create the column families of the trees of the tree column family layout with `options`.
+    pub fn with_tree_cf_options(mut self, options: Options) -> Self {
+        self.tree_cf_options = Arc::new(options);
+        self
+    }
+
+    /// Record every committed update of a tree as a version and retain the `window` latest versions of each tree, so the
+    /// trees can be read at any retained version. The updates are always committed atomically when it's enabled.
+    pub fn with_history_window(mut self, window: u64) -> Self {
+        self.history_window = window;
+        self
+    }
+
+    /// Remove the records of the abandoned trees in a background thread, the trees are abandoned by `reset_smt`.
+    pub fn spawn_cleanup(&self) {
+        let server = self.clone();
+        thread::spawn(move || match server.cleanup_abandoned_trees() {
+            Ok(removed) => info!("abandoned trees cleaned up, removed keys num = {}", removed),
+            Err(e) => error!("cannot clean up abandoned trees : {}", e),
+        });
+    }
+
+    // Remove the records of all the abandoned trees, returns the number of removed keys.
+    fn cleanup_abandoned_trees(&self) -> Result<usize, Error> {
+        let _guard = self.cleanup_lock.lock().map_err(|e| {
+            error!("cannot lock cleanup : {}", &e);
+            Error::Custom(e.to_string())
+        })?;
+        let mut removed = 0;
+        loop {
+            let tree_names = get_abandoned_trees(&*self.read_db()?);
+            if tree_names.is_empty() {
+                return Ok(removed);
+            }
+            for tree_name in tree_names {
+                removed += self.purge_tree(&tree_name)?;
+                let db = self.read_db()?;
+                let tx = db.transaction_default();
+                if let Err(e) = forget_abandoned_tree(&tx, &tree_name) {
+                    error!(
+                        "cannot forget abandoned tree {} : {}",
+                        tree_name_to_string(&tree_name),
+                        &e
+                    );
+                    return Err(Error::Custom(e.to_string()));
+                }
+                commit_to_database(&tx)?;
+                info!(
+                    "abandoned tree {} cleaned up",
+                    tree_name_to_string(&tree_name)
+                );
+            }
+        }
+    }
+
+    // The name the records of the tree `smt_name` are currently stored under, see `get_smt_tree_name`.
+    fn resolve_tree_name(&self, smt_name: &str) -> Result<Vec<u8>, Error> {
+        resolve_tree_name(&self.read_db()?, smt_name)
+    }
+
+    // Lock the database for an update of the tree `smt_name` and resolve the name its records are stored under, the
+    // column family of the tree is created first for the tree column family layout.
+    fn lock_tree_for_update(
+        &self,
+        smt_name: &str,
+    ) -> Result<(RwLockReadGuard<OptimisticTransactionDB>, Vec<u8>), Error> {
+        loop {
+            let db = self.read_db()?;
+            let tree_name = resolve_tree_name(&db, smt_name)?;
+            if B::LAYOUT != StoreLayout::TreeColumnFamily
+                || db.cf_handle(&tree_column_family_name(&tree_name)).is_some()
+            {
+                return Ok((db, tree_name));
+            }
+            // the version or the alias of the tree may change while the lock is released, so it's resolved again
+            drop(db);
+            self.create_tree_column_family(&tree_name)?;
+        }
+    }
+
+    fn read_db(&self) -> Result<RwLockReadGuard<OptimisticTransactionDB>, Error> {
+        self.db.read().map_err(|e| {
+            error!("cannot lock database : {}", &e);
+            Error::Custom(e.to_string())
+        })
+    }
+
+    fn write_db(&self) -> Result<RwLockWriteGuard<OptimisticTransactionDB>, Error> {
+        self.db.write().map_err(|e| {
+            error!("cannot lock database : {}", &e);
+            Error::Custom(e.to_string())
+        })
+    }
+
+    // Create the column family of the tree `tree_name` if it does not exist yet, it's only needed by the tree column
+    // family layout before the tree is written.
+    fn create_tree_column_family(&self, tree_name: &[u8]) -> Result<(), Error> {
+        if B::LAYOUT != StoreLayout::TreeColumnFamily {
+            return Ok(());
+        }
+        let cf_name = tree_column_family_name(tree_name);
+        if self.read_db()?.cf_handle(&cf_name).is_some() {
+            return Ok(());
+        }
+        let mut db = self.write_db()?;
+        // another request may have created it while the lock was released
+        if db.cf_handle(&cf_name).is_some() {
+            return Ok(());
+        }
+        match db.create_cf(&cf_name, &self.tree_cf_options) {
+            Ok(_) => {
+                info!(
+                    "column family {} of smt {} created",
+                    cf_name,
+                    tree_name_to_string(tree_name)
+                );
+                Ok(())
+            }
+            Err(e) => {
+                error!("cannot create column family {} : {}", cf_name, &e);
+                Err(Error::Custom(e.to_string()))
+            }
+        }
+    }
+
+    // Open the store of the tree `tree_name` on `inner`, which is a transaction or a snapshot of `db`, see
+    // `StoreBackend::open`.
+    fn tree_store<'a, T, W>(
+        &self,
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+        inner: &'a T,
+    ) -> Result<TreeStore<'a, T, W>, Error> {
+        B::open(db, tree_name, inner, self.encoding).map_err(|e| {
+            error!(
+                "cannot open store, smt_tree = {}, err = {}",
+                tree_name_to_string(tree_name),
+                &e
+            );
+            Error::Custom(e.to_string())
+        })
+    }
+
+    // Open the tree `tree_name` on `inner`, see `tree_store`.
+    fn tree<'a, T, W>(
+        &self,
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+        inner: &'a T,
+    ) -> Result<TreeStoreSMT<'a, T, W>, Error>
+    where
+        T: Get<ReadOptions> + GetCF<ReadOptions>,
+    {
+        let store = self.tree_store(db, tree_name, inner)?;
+        match TreeStoreSMT::new_with_store(store) {
+            Ok(r) => Ok(r),
+            Err(e) => {
+                error!(
+                    "cannot initialize database store, smt_tree = {}, err = {}",
+                    tree_name_to_string(tree_name),
+                    &e
+                );
+                Err(Error::Custom(e.to_string()))
+            }
+        }
+    }
+
+    // Open the tree `tree_name` on the transaction `tx` for an update, the previous content of the written records is
+    // journaled if the history is enabled.
+    fn journaled_tree<'a, T, W>(
+        &self,
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+        tx: &'a T,
+    ) -> Result<JournalStoreSMT<'a, T, W>, Error>
+    where
+        T: Get<ReadOptions> + GetCF<ReadOptions>,
+    {
+        let store = JournalStore::new(self.tree_store(db, tree_name, tx)?, self.history_window > 0);
+        match JournalStoreSMT::new_with_store(store) {
+            Ok(r) => Ok(r),
+            Err(e) => {
+                error!(
+                    "cannot initialize database store, smt_tree = {}, err = {}",
+                    tree_name_to_string(tree_name),
+                    &e
+                );
+                Err(Error::Custom(e.to_string()))
+            }
+        }
+    }
+
+    // Record the update of the tree `tree_name` journaled by `smt` as a new version in `tx`, if the history is enabled.
+    fn record_version<W>(
+        &self,
+        tx: &OptimisticTransaction,
+        tree_name: &[u8],
+        smt: &JournalStoreSMT<OptimisticTransaction, W>,
+        tag: Option<u64>,
+    ) -> Result<Option<u64>, Error> {
+        if self.history_window == 0 {
+            return Ok(None);
+        }
+        match history::record_version(
+            tx,
+            tree_name,
+            smt.root(),
+            tag,
+            smt.store().changes(),
+            self.history_window,
+        ) {
+            Ok(version) => {
+                info!(
+                    "smt {} version {} recorded",
+                    tree_name_to_string(tree_name),
+                    version
+                );
+                Ok(Some(version))
+            }
+            Err(e) => {
+                error!(
+                    "cannot record version of smt {} : {}",
+                    tree_name_to_string(tree_name),
+                    &e
+                );
+                Err(Error::Custom(e.to_string()))
+            }
+        }
+    }
+
+    // The retained version of the tree `tree_name` selected by `at`, `None` selects the current state.
+    fn resolve_version<T: Iterate>(
+        &self,
+        snapshot: &T,
+        tree_name: &[u8],
+        at: &Option<SmtVersionQuery>,
+    ) -> Result<Option<VersionRecord>, Error> {
+        let at = match at {
+            Some(at) if at.version.is_some() || at.tag.is_some() || at.root.is_some() => at,
+            _ => return Ok(None),
+        };
+        let versions = match history::read_versions(snapshot, tree_name) {
+            Ok(versions) => versions,
+            Err(e) => {
+                error!(
+                    "cannot read versions of smt {} : {}",
+                    tree_name_to_string(tree_name),
+                    &e
+                );
+                return Err(Error::Custom(e.to_string()));
+            }
+        };
+        let root = at.root.as_ref().map(|r| H256::from(r.0));
+        let found = versions.into_iter().rev().find(|r| {
+            at.version.map_or(true, |v| r.version == v)
+                && at.tag.map_or(true, |t| r.tag == Some(t))
+                && root.map_or(true, |root| r.root == root)
+        });
+        match found {
+            Some(record) => Ok(Some(record)),
+            None => {
+                let err_str = format!(
+                    "no retained version of smt {} matches {}",
+                    tree_name_to_string(tree_name),
+                    generate_version_query_info(at)
+                );
+                error!("{}", err_str);
+                Err(Error::Custom(err_str))
+            }
+        }
+    }
+
+    // Open the store of the tree `tree_name` at `version` on `snapshot`, see `tree_store`.
+    fn versioned_tree_store<'a, T, W>(
+        &self,
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+        snapshot: &'a T,
+        version: Option<u64>,
+    ) -> Result<VersionedStore<'a, T, TreeStore<'a, T, W>>, Error> {
+        Ok(VersionedStore::new(
+            snapshot,
+            tree_name,
+            version,
+            self.tree_store(db, tree_name, snapshot)?,
+        ))
+    }
+
+    // Open the tree `tree_name` on `snapshot` at the version selected by `at`, and check it has the root recorded for
+    // the version.
+    fn versioned_tree<'a, T, W>(
+        &self,
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+        snapshot: &'a T,
+        at: &Option<SmtVersionQuery>,
+    ) -> Result<VersionedStoreSMT<'a, T, W>, Error>
+    where
+        T: Iterate + Get<ReadOptions> + GetCF<ReadOptions>,
+    {
+        let record = self.resolve_version(snapshot, tree_name, at)?;
+        let store =
+            self.versioned_tree_store(db, tree_name, snapshot, record.as_ref().map(|r| r.version))?;
+        let smt = match VersionedStoreSMT::new_with_store(store) {
+            Ok(r) => r,
+            Err(e) => {
+                error!(
+                    "cannot initialize database store, smt_tree = {}, err = {}",
+                    tree_name_to_string(tree_name),
+                    &e
+                );
+                return Err(Error::Custom(e.to_string()));
+            }
+        };
+        if let Some(record) = record {
+            if smt.root() != &record.root {
+                let err_str = format!(
+                    "the history of smt {} is inconsistent, the root of version {} is {} but {} is recorded",
+                    tree_name_to_string(tree_name),
+                    record.version,
+                    slice_to_hex_string(smt.root().as_slice()),
+                    slice_to_hex_string(record.root.as_slice())
+                );
+                error!("{}", err_str);
+                return Err(Error::Custom(err_str));
+            }
+        }
+        Ok(smt)
+    }
+
+    // The column families and the key prefixes of all the records of the tree `tree_name`, see `StoreBackend::ranges`.
+    fn tree_ranges<'a>(
+        &self,
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+    ) -> Result<Vec<(Option<&'a ColumnFamily>, Vec<u8>)>, Error> {
+        B::ranges(db, tree_name).map_err(|e| {
+            error!(
+                "cannot read ranges, smt_tree = {}, err = {}",
+                tree_name_to_string(tree_name),
+                &e
+            );
+            Error::Custom(e.to_string())
+        })
+    }
+
+    // Physically remove all the records of the tree `tree_name`, returns the number of removed keys.
+    //
+    // OptimisticTransactionDB does not support delete_range, so the keys of the tree are swept from a snapshot and deleted
+    // in chunks, each chunk in a transaction of its own, an interrupted purge leaves part of the tree behind and is
+    // resumed by purging the tree again. The column family of a tree of the tree column family layout is dropped
+    // instead, without sweeping it, and the number of removed keys is only the estimate of rocksdb. The history of the
+    // tree is deleted last.
+    fn purge_tree(&self, tree_name: &[u8]) -> Result<usize, Error> {
+        if B::LAYOUT == StoreLayout::TreeColumnFamily {
+            let mut db = self.write_db()?;
+            let cf_name = tree_column_family_name(tree_name);
+            let estimated = db
+                .cf_handle(&cf_name)
+                .map(|col| estimated_keys_num(&db, col));
+            let removed = match estimated {
+                Some(estimated) => {
+                    if let Err(e) = db.drop_cf(&cf_name) {
+                        error!("cannot drop column family {} : {}", cf_name, &e);
+                        return Err(Error::Custom(e.to_string()));
+                    }
+                    info!(
+                        "column family {} of smt {} dropped",
+                        cf_name,
+                        tree_name_to_string(tree_name)
+                    );
+                    estimated
+                }
+                None => {
+                    info!(
+                        "column family {} of smt {} does not exist",
+                        cf_name,
+                        tree_name_to_string(tree_name)
+                    );
+                    0
+                }
+            };
+            let tx = db.transaction_default();
+            let removed = removed + delete_history(&tx, tree_name)?;
+            commit_to_database(&tx)?;
+            return Ok(removed);
+        }
+
+        let db = self.read_db()?;
+        let snapshot = db.snapshot();
+        let mut removed = 0;
+        for (col, prefix) in self.tree_ranges(&db, tree_name)? {
+            let mut keys = range_iterator(&snapshot, col, &prefix)?.map(|(k, _)| k);
+            loop {
+                let chunk: Vec<Box<[u8]>> = keys.by_ref().take(CHUNK_SIZE).collect();
+                if chunk.is_empty() {
+                    break;
+                }
+                let tx = db.transaction_default();
+                for k in chunk.iter() {
+                    let deleted = match col {
+                        None => tx.delete(k),
+                        Some(col) => tx.delete_cf(col, k),
+                    };
+                    if let Err(e) = deleted {
+                        let err_str = format!(
+                            "cannot delete key, smt_name = {}, key = {}, err = {}",
+                            tree_name_to_string(tree_name),
+                            slice_to_hex_string(k),
+                            e
+                        );
+                        error!("{}", err_str);
+                        return Err(Error::Custom(err_str));
+                    }
+                }
+                commit_to_database(&tx)?;
+                removed += chunk.len();
+            }
+        }
+        let tx = db.transaction_default();
+        removed += delete_history(&tx, tree_name)?;
+        commit_to_database(&tx)?;
+        Ok(removed)
+    }
+
+    // Delete the branch and leaf records of the tree `tree_name` which are not reachable from its root, e.g. left behind
+    // by an interrupted chunked commit.
+    //
+    // The reachable keys are collected from a snapshot taken after the transaction, a concurrent update which
+    // writes a deleted key again makes the commit fail instead of losing the record.
+    fn collect_garbage(&self, tree_name: &[u8]) -> Result<ResponseGc, Error> {
+        let db = self.read_db()?;
+        let tx = transaction(&db, true);
+        let snapshot = db.snapshot();
+        let rocksdb_store_smt = self.tree::<_, ()>(&db, tree_name, &snapshot)?;
+        let root = *rocksdb_store_smt.root();
+        let ranges = self.tree_ranges(&db, tree_name)?;
+
+        let reachable = match reachable_keys::<SmtValue, _>(rocksdb_store_smt.store()) {
+            Ok(reachable) => reachable,
+            Err(e) => {
+                error!(
+                    "cannot walk smt tree {} : {}",
+                    tree_name_to_string(tree_name),
+                    &e
+                );
+                return Err(Error::Custom(e.to_string()));
+            }
+        };
+
+        let mut removed_keys = 0;
+        let mut reclaimed_bytes = 0u64;
+        for (col, prefix) in ranges.iter() {
+            for (k, v) in range_records(&snapshot, *col, prefix)? {
+                // the leaf keys are 32 bytes long and the branch keys 33
+                if !matches!(k.len() - prefix.len(), 32 | 33)
+                    || reachable.contains(&k[prefix.len()..])
+                {
+                    continue;
+                }
+                let deleted = match col {
+                    None => tx.delete(&k),
+                    Some(col) => tx.delete_cf(col, &k),
+                };
+                if let Err(e) = deleted {
+                    let err_str = format!(
+                        "cannot delete key, smt_name = {}, key = {}, err = {}",
+                        tree_name_to_string(tree_name),
+                        slice_to_hex_string(&k),
+                        e
+                    );
+                    error!("{}", err_str);
+                    return Err(Error::Custom(err_str));
+                }
+                removed_keys += 1;
+                reclaimed_bytes += (k.len() + v.len()) as u64;
+            }
+        }
+        commit_to_database(&tx)?;
+        Ok(ResponseGc {
+            root: SmtRoot::from(&root),
+            removed_keys,
+            reclaimed_bytes,
+        })
+    }
+
+    // Copy all the records of the tree `src` to the empty tree `dst` in a single transaction, the copy is only committed
+    // if the root of `dst` equals the root of `src` afterwards.
+    //
+    // Both trees are read from a snapshot taken after the transaction. Every update of a tree writes its root branch
+    // record, the one of `src` is read for update so a concurrent update of `src` makes the commit fail, and so does a
+    // concurrent update of `dst`, which writes the same root branch record as the copy. An empty `src` of the tree
+    // column family layout has no column family to read, so a concurrent update creating it is not detected.
+    //
+    // The records written by the copy are not journaled, so the history of `dst` is deleted with the copy, its versions
+    // recorded before can no longer be read or rolled back to.
+    fn copy_tree(&self, src: &str, dst: &str) -> Result<ResponseCopy, Error> {
+        let (db, dst_tree) = self.lock_tree_for_update(dst)?;
+        let src_tree = resolve_tree_name(&db, src)?;
+        if src_tree == dst_tree {
+            let err_str = format!("cannot copy smt {} to itself", src);
+            error!("{}", err_str);
+            return Err(Error::Custom(err_str));
+        }
+        let tx = transaction(&db, true);
+        let snapshot = db.snapshot();
+        let src_ranges = self.tree_ranges(&db, &src_tree)?;
+        let dst_ranges = self.tree_ranges(&db, &dst_tree)?;
+        for (col, prefix) in dst_ranges.iter() {
+            if !range_keys(&snapshot, *col, prefix)?.is_empty() {
+                let err_str = format!("cannot copy smt {} to {}, {} is not empty", src, dst, dst);
+                error!("{}", err_str);
+                return Err(Error::Custom(err_str));
+            }
+        }
+        // the branch records are listed first
+        if let Some((col, prefix)) = src_ranges.first() {
+            let root_key = [
+                prefix.as_slice(),
+                &branch_key_to_vec(&BranchKey::new(u8::MAX, H256::zero())),
+            ]
+            .concat();
+            let read = match col {
+                None => tx.get_for_update(&root_key),
+                Some(col) => tx.get_for_update_cf(col, &root_key),
+            };
+            if let Err(e) = read {
+                error!("cannot read root of smt {} : {}", src, &e);
+                return Err(Error::Custom(e.to_string()));
+            }
+        }
+
+        // the ranges of both trees are listed in the same order, a tree without a column family has none
+        let mut copied_keys = 0;
+        for ((src_col, src_prefix), (dst_col, dst_prefix)) in
+            src_ranges.iter().zip(dst_ranges.iter())
+        {
+            for (k, v) in range_records(&snapshot, *src_col, src_prefix)? {
+                let dst_key = [dst_prefix.as_slice(), &k[src_prefix.len()..]].concat();
+                let written = match dst_col {
+                    None => tx.put(&dst_key, &v),
+                    Some(col) => tx.put_cf(col, &dst_key, &v),
+                };
+                if let Err(e) = written {
+                    let err_str = format!(
+                        "cannot put key, smt_name = {}, key = {}, err = {}",
+                        dst,
+                        slice_to_hex_string(&dst_key),
+                        e
+                    );
+                    error!("{}", err_str);
+                    return Err(Error::Custom(err_str));
+                }
+                copied_keys += 1;
+            }
+        }
+
+        let src_root = *self.tree::<_, ()>(&db, &src_tree, &snapshot)?.root();
+        let dst_root = *self.tree::<_, ()>(&db, &dst_tree, &tx)?.root();
+        if src_root != dst_root {
+            // the transaction is dropped without a commit
+            let err_str = format!(
+                "the root of the copy {} is {}, but the root of smt {} is {}",
+                dst,
+                slice_to_hex_string(dst_root.as_slice()),
+                src,
+                slice_to_hex_string(src_root.as_slice())
+            );
+            error!("{}", err_str);
+            return Err(integrity_error(err_str));
+        }
+        let deleted = delete_history(&tx, &dst_tree)?;
+        commit_to_database(&tx)?;
+        if deleted > 0 {
+            info!(
+                "history of smt {} deleted by the copy of {}, deleted keys num = {}",
+                dst, src, deleted
+            );
+        }
+        Ok(ResponseCopy {
+            root: SmtRoot::from(&src_root),
+            copied_keys,
+        })
     }
 }
 
@@ -54,6 +714,7 @@ pub trait Rpc {
         opt: Opt,
         smt_name: &str,
         data: Vec<Pair>,
+        expected_root: Option<SmtRoot>,
     ) -> Result<Response, Error>;
 
     #[method(name = "update_db_smt_middle")]
@@ -62,29 +723,100 @@ pub trait Rpc {
         opt: Opt,
         smt_name: &str,
         data: Vec<Pair>,
+        expected_root: Option<SmtRoot>,
     ) -> Result<ResponseSequence, Error>;
 
     #[method(name = "get_smt_root")]
-    async fn get_smt_root(&self, smt_name: &str) -> Result<SmtRoot, Error>;
+    async fn get_smt_root(
+        &self,
+        smt_name: &str,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<SmtRoot, Error>;
+
+    #[method(name = "get_smt_value")]
+    async fn get_smt_value(
+        &self,
+        smt_name: &str,
+        key: SmtKey,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<Option<SmtValue>, Error>;
+
+    #[method(name = "get_smt_values")]
+    async fn get_smt_values(
+        &self,
+        smt_name: &str,
+        keys: Vec<SmtKey>,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<Vec<SmtLeaf>, Error>;
+
+    #[method(name = "get_smt_proof")]
+    async fn get_smt_proof(
+        &self,
+        smt_name: &str,
+        keys: Vec<SmtKey>,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<ResponseProof, Error>;
+
+    #[method(name = "get_smt_non_membership_proof")]
+    async fn get_smt_non_membership_proof(
+        &self,
+        smt_name: &str,
+        key: SmtKey,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<ResponseNonMembershipProof, Error>;
+
+    #[method(name = "get_smt_versions")]
+    async fn get_smt_versions(&self, smt_name: &str) -> Result<Vec<SmtVersion>, Error>;
+
+    #[method(name = "rollback_smt")]
+    async fn rollback_smt(
+        &self,
+        smt_name: &str,
+        target: SmtVersionQuery,
+    ) -> Result<ResponseRollback, Error>;
+
+    #[method(name = "verify_smt_proof")]
+    async fn verify_smt_proof(
+        &self,
+        root: SmtRoot,
+        proof: SmtProof,
+        data: Vec<Pair>,
+    ) -> Result<bool, Error>;
 
     #[method(name = "delete_smt")]
     async fn delete_smt(&self, smt_name: &str) -> Result<bool, Error>;
+
+    #[method(name = "purge_smt")]
+    async fn purge_smt(&self, smt_name: &str) -> Result<usize, Error>;
+
+    #[method(name = "gc_smt")]
+    async fn gc_smt(&self, smt_name: &str) -> Result<ResponseGc, Error>;
+
+    #[method(name = "reset_smt")]
+    async fn reset_smt(&self, smt_name: &str) -> Result<u64, Error>;
+
+    #[method(name = "copy_smt")]
+    async fn copy_smt(&self, src: &str, dst: &str) -> Result<ResponseCopy, Error>;
+
+    #[method(name = "swap_smt")]
+    async fn swap_smt(&self, smt_name1: &str, smt_name2: &str) -> Result<bool, Error>;
 }
 
 #[async_trait]
-impl RpcServer for RpcServerImpl {
+impl<B: StoreBackend> RpcServer for RpcServerImpl<B> {
     async fn build_memory_smt(
         &self,
         opt: Opt,
         _smt_name: &str,
         kvs_in: Vec<Pair>,
     ) -> Result<Response, Error> {
-        let (get_root, get_proof) = (opt.get_root, opt.get_proof);
+        let (get_root, get_proof, proof_mode) = (opt.get_root, opt.get_proof, opt.proof_mode);
 
         info!(
-            "building smt in memory start: get_root = {}, get_proof = {}, keys_len = {}, {}",
+            "building smt in memory start: get_root = {}, get_proof = {}, proof_mode = {:?}, keys_len = {}, {}",
             get_root,
             get_proof,
+            proof_mode,
             kvs_in.len(),
             generate_kvs_info(&kvs_in)
         );
@@ -126,43 +858,18 @@ impl RpcServer for RpcServerImpl {
         };
 
         info!("generate proof");
+        let keys: Vec<H256> = kvs_in.clone().into_iter().map(|k| k.key.0.into()).collect();
         let smt_proofs = if !get_proof {
             default_merkel_proof()
+        } else if !proof_mode.per_key() {
+            Vec::new()
         } else {
-            let keys: Vec<H256> = kvs_in.clone().into_iter().map(|k| k.key.0.into()).collect();
-
-            keys.par_iter()
-                .filter_map(|k| {
-                    let mut vec = Vec::new();
-                    vec.push(*k);
-                    let proof = match memory_store_smt.merkle_proof(vec.clone()) {
-                        Ok(proof) => Some(proof),
-                        Err(e) => {
-                            error!(
-                                "get merkle proof failed!  key= {} : err = {}",
-                                slice_to_hex_string(k.as_slice()),
-                                &e
-                            );
-                            None
-                        }
-                    };
-                    if let Some(p) = proof {
-                        match p.clone().compile(vec) {
-                            Ok(compiled_proof) => {
-                                let k = slice_to_hex_string(k.to_h256().as_slice());
-                                let v = slice_to_hex_string(compiled_proof.0.as_slice());
-                                Some((k, v))
-                            }
-                            Err(e) => {
-                                error!("unable to generate compiled proof : {}", &e);
-                                None
-                            }
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect()
+            generate_proofs(&memory_store_smt, &keys)
+        };
+        let multi_proof = if get_proof && proof_mode.combined() {
+            Some(generate_multi_proof(&memory_store_smt, keys)?)
+        } else {
+            None
         };
 
         let hashmap_proofs: HashMap<_, _> = smt_proofs.into_par_iter().collect();
@@ -171,11 +878,15 @@ impl RpcServer for RpcServerImpl {
             Response {
                 root: SmtRoot::default(),
                 proofs: hashmap_proofs,
+                multi_proof,
+                ..Default::default()
             }
         } else {
             Response {
                 root: smt_root,
                 proofs: hashmap_proofs,
+                multi_proof,
+                ..Default::default()
             }
         };
         debug!("response = {}", generate_response_debug(&r));
@@ -188,27 +899,31 @@ impl RpcServer for RpcServerImpl {
         opt: Opt,
         smt_name: &str,
         kvs_in: Vec<Pair>,
+        expected_root: Option<SmtRoot>,
     ) -> Result<Response, Error> {
-        let (get_root, get_proof) = (opt.get_root, opt.get_proof);
+        // a compare-and-swap update is only meaningful if the whole batch is committed at once, and so is a version
+        let (get_root, get_proof, proof_mode, atomic, dry_run, get_previous) = (
+            opt.get_root,
+            opt.get_proof,
+            opt.proof_mode,
+            opt.atomic || expected_root.is_some() || self.history_window > 0,
+            opt.dry_run,
+            opt.get_previous,
+        );
 
-        info!("update smt in the database start: smt_name = {}, get_root = {}, get_proof = {}, kvs_len = {}, {}",
-        smt_name, get_root, get_proof, kvs_in.len(), generate_kvs_info(&kvs_in));
+        info!("update smt in the database start: smt_name = {}, get_root = {}, get_proof = {}, proof_mode = {:?}, atomic = {}, dry_run = {}, get_previous = {}, expected_root = {}, kvs_len = {}, {}",
+        smt_name, get_root, get_proof, proof_mode, atomic, dry_run, get_previous, generate_expected_root_info(&expected_root), kvs_in.len(), generate_kvs_info(&kvs_in));
 
         debug!("{}", generate_kvs_debug(&kvs_in));
 
+        let (db, tree_name) = self.lock_tree_for_update(smt_name)?;
+
         info!("create transaction ");
-        let tx = self.db.transaction_default();
+        let tx = transaction(&db, expected_root.is_some() || self.history_window > 0);
 
         info!("get handle of smt store: {}", smt_name);
-        let mut rocksdb_store_smt = match DefaultStoreMultiSMT::new_with_store(
-            DefaultStoreMultiTree::new(smt_name.as_bytes(), &tx),
-        ) {
-            Ok(r) => r,
-            Err(e) => {
-                error!("cannot initialize database store : {}", &e);
-                return Err(Error::Custom(e.to_string()));
-            }
-        };
+        let mut rocksdb_store_smt = self.journaled_tree(&db, &tree_name, &tx)?;
+        check_expected_root(smt_name, rocksdb_store_smt.root(), &expected_root)?;
 
         let kvs: Vec<(H256, SmtValue)> = kvs_in
             .clone()
@@ -216,7 +931,21 @@ impl RpcServer for RpcServerImpl {
             .map(|k| (k.key.0.into(), k.value))
             .collect();
 
-        info!("update start， keys num = {}", kvs.len());
+        // read the previous state through the same transaction, so it is exactly the state the batch is applied to
+        let (previous_root, previous_values, previous_proof) = if get_previous {
+            info!("generate previous state");
+            let keys: Vec<H256> = kvs.iter().map(|(k, _)| *k).collect();
+            let (root, values, proof) = generate_previous_state(&rocksdb_store_smt, &keys)?;
+            (Some(root), Some(values), Some(proof))
+        } else {
+            (None, None, None)
+        };
+
+        info!(
+            "update start， keys num = {}, atomic = {}",
+            kvs.len(),
+            atomic
+        );
         for chunk in kvs.chunks(CHUNK_SIZE) {
             let _ = match rocksdb_store_smt.update_all(chunk.to_vec()) {
                 Ok(_) => {}
@@ -228,68 +957,68 @@ impl RpcServer for RpcServerImpl {
                     return Err(Error::Custom(e.to_string()));
                 }
             };
-            commit_to_database(&tx)?;
+            // a failure in a later chunk leaves the chunks committed here in the database
+            if !atomic && !dry_run {
+                commit_to_database(&tx)?;
+            }
         }
+        // the transaction is dropped without commit on any error above, so either the whole batch lands or none of it
+        let version = if atomic && !dry_run {
+            let version = self.record_version(&tx, &tree_name, &rocksdb_store_smt, opt.tag)?;
+            commit_to_database_expecting_root(&tx, &expected_root)?;
+            version
+        } else {
+            None
+        };
         info!("update end");
 
         let smt_root = rocksdb_store_smt.root().into();
 
         info!("generate proof");
+        let keys: Vec<H256> = kvs_in
+            .clone()
+            .into_par_iter()
+            .map(|k| k.key.0.into())
+            .collect();
         let smt_proofs = if !get_proof {
             default_merkel_proof()
+        } else if !proof_mode.per_key() {
+            Vec::new()
         } else {
-            let keys: Vec<H256> = kvs_in
-                .clone()
-                .into_par_iter()
-                .map(|k| k.key.0.into())
-                .collect();
-
-            keys.par_iter()
-                .filter_map(|k| {
-                    let mut vec = vec![];
-                    vec.push(*k);
-                    let proof = match rocksdb_store_smt.merkle_proof(vec.clone()) {
-                        Ok(proof) => Some(proof),
-                        Err(e) => {
-                            error!(
-                                "get merkle proof failed!  key= {} : err = {}",
-                                slice_to_hex_string(k.as_slice()),
-                                &e
-                            );
-                            None
-                        }
-                    };
-
-                    if let Some(p) = proof {
-                        match p.clone().compile(vec) {
-                            Ok(compiled_proof) => {
-                                let k = slice_to_hex_string(k.to_h256().as_slice());
-                                let v = slice_to_hex_string(compiled_proof.0.as_slice());
-                                Some((k, v))
-                            }
-                            Err(e) => {
-                                error!("unable to generate compiled proof : {}", &e);
-                                None
-                            }
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>()
+            generate_proofs(&rocksdb_store_smt, &keys)
+        };
+        let multi_proof = if get_proof && proof_mode.combined() && !keys.is_empty() {
+            Some(generate_multi_proof(&rocksdb_store_smt, keys)?)
+        } else {
+            None
         };
 
+        // the proofs are read through the transaction, so it can only be rolled back after they are generated
+        if dry_run {
+            rollback_database(&tx)?;
+        }
+
         let hashmap_proofs: HashMap<_, _> = smt_proofs.into_par_iter().collect();
 
         let r = if !get_root {
             Response {
                 root: SmtRoot::default(),
                 proofs: hashmap_proofs,
+                multi_proof,
+                previous_root,
+                previous_values,
+                previous_proof,
+                version,
             }
         } else {
             Response {
                 root: smt_root,
                 proofs: hashmap_proofs,
+                multi_proof,
+                previous_root,
+                previous_values,
+                previous_proof,
+                version,
             }
         };
 
@@ -303,29 +1032,25 @@ impl RpcServer for RpcServerImpl {
         opt: Opt,
         smt_name: &str,
         kvs_in: Vec<Pair>,
+        expected_root: Option<SmtRoot>,
     ) -> Result<ResponseSequence, Error> {
-        let (get_root, get_proof) = (opt.get_root, opt.get_proof);
+        let (get_root, get_proof, dry_run) = (opt.get_root, opt.get_proof, opt.dry_run);
 
-        info!("update smt in the database in order start: smt_name = {}, get_root = {}, get_proof = {}, kvs_len = {}, {}",
-        smt_name, get_root, get_proof, kvs_in.len(), generate_kvs_info(&kvs_in));
+        info!("update smt in the database in order start: smt_name = {}, get_root = {}, get_proof = {}, dry_run = {}, expected_root = {}, kvs_len = {}, {}",
+        smt_name, get_root, get_proof, dry_run, generate_expected_root_info(&expected_root), kvs_in.len(), generate_kvs_info(&kvs_in));
 
         let kvs_len = kvs_in.len();
 
         debug!("{}", generate_kvs_debug(&kvs_in));
 
+        let (db, tree_name) = self.lock_tree_for_update(smt_name)?;
+
         info!("create transaction ");
-        let tx = self.db.transaction_default();
+        let tx = transaction(&db, expected_root.is_some() || self.history_window > 0);
 
         debug!("get handle of smt store: {}", smt_name);
-        let mut rocksdb_store_smt = match DefaultStoreMultiSMT::new_with_store(
-            DefaultStoreMultiTree::new(smt_name.as_bytes(), &tx),
-        ) {
-            Ok(r) => r,
-            Err(e) => {
-                error!("cannot initialize database store : {}", &e);
-                return Err(Error::Custom(e.to_string()));
-            }
-        };
+        let mut rocksdb_store_smt = self.journaled_tree(&db, &tree_name, &tx)?;
+        check_expected_root(smt_name, rocksdb_store_smt.root(), &expected_root)?;
 
         let kvs: Vec<(H256, SmtValue)> = kvs_in
             .clone()
@@ -335,10 +1060,12 @@ impl RpcServer for RpcServerImpl {
 
         let mut hashmap_roots = HashMap::new();
         let mut hashmap_proofs = HashMap::new();
-        for (k, v) in kvs {
+        let mut steps = Vec::with_capacity(kvs_len);
+        for (index, (k, v)) in kvs.into_iter().enumerate() {
+            let root_before = *rocksdb_store_smt.root();
             {
                 match rocksdb_store_smt.update(k, v.clone()) {
-                    Ok(_) => {},
+                    Ok(_) => {}
                     Err(e) => {
                         let err_str = format!(
                             "cannot update smt, err = {}, key = {}, value = {}",
@@ -379,6 +1106,14 @@ impl RpcServer for RpcServerImpl {
                     let proof = slice_to_hex_string(cp.0.as_slice());
                     hashmap_roots.insert(slice_to_hex_string(k.as_slice()), root);
                     hashmap_proofs.insert(slice_to_hex_string(k.as_slice()), proof);
+                    steps.push(SequenceStep {
+                        index,
+                        key: SmtKey::from(&k),
+                        value: v,
+                        root_before: SmtRoot::from(&root_before),
+                        root_after: SmtRoot::from(smt_root),
+                        proof: SmtProof(cp.0),
+                    });
                 }
                 Err(e) => {
                     let err_str = format!(
@@ -392,9 +1127,17 @@ impl RpcServer for RpcServerImpl {
             }
         } //end    for (k, v) in kvs {
 
-        commit_to_database(&tx)?;
+        let version = if dry_run {
+            rollback_database(&tx)?;
+            None
+        } else {
+            let version = self.record_version(&tx, &tree_name, &rocksdb_store_smt, opt.tag)?;
+            commit_to_database_expecting_root(&tx, &expected_root)?;
+            version
+        };
 
-        if hashmap_proofs.len() != kvs_len {
+        // the maps hold one entry per distinct key, count the steps so repeated keys are not mistaken for failures
+        if steps.len() != kvs_len {
             let err_str = "some keys cannot generate proof";
             error!("{}", err_str);
             return Err(Error::Custom(err_str.to_string()));
@@ -403,11 +1146,15 @@ impl RpcServer for RpcServerImpl {
             ResponseSequence {
                 roots: hashmap_roots,
                 proofs: hashmap_proofs,
+                steps,
+                version,
             }
         } else {
             ResponseSequence {
                 roots: hashmap_roots,
                 proofs: hashmap_proofs,
+                steps,
+                version,
             }
         };
         debug!("{}", generate_response_sequence_debug(&r));
@@ -415,104 +1162,423 @@ impl RpcServer for RpcServerImpl {
         info!("update smt in the database in order end");
         Ok(r)
     }
-    async fn get_smt_root(&self, smt_name: &str) -> Result<SmtRoot, Error> {
-        info!("get smt root of {}", smt_name);
-        let snapshot = self.db.snapshot();
-        let rocksdb_store_smt =
-            match DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::<_, ()>::new(
-                smt_name.as_bytes(),
-                &snapshot,
-            )) {
-                Ok(r) => r,
+    async fn get_smt_root(
+        &self,
+        smt_name: &str,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<SmtRoot, Error> {
+        info!(
+            "get smt root of {}, at = {}",
+            smt_name,
+            generate_at_info(&at)
+        );
+        let db = self.read_db()?;
+        let tree_name = resolve_tree_name(&db, smt_name)?;
+        let snapshot = db.snapshot();
+        let rocksdb_store_smt = self.versioned_tree::<_, ()>(&db, &tree_name, &snapshot, &at)?;
+
+        let smt_root: SmtRoot = rocksdb_store_smt.root().into();
+        info!(
+            "get smt root end, root = {}",
+            slice_to_hex_string(&smt_root.0)
+        );
+        Ok(smt_root)
+    }
+
+    async fn get_smt_value(
+        &self,
+        smt_name: &str,
+        key: SmtKey,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<Option<SmtValue>, Error> {
+        let leaves = self.get_smt_values(smt_name, vec![key], at).await?;
+        Ok(leaves.into_iter().next().and_then(|l| l.value))
+    }
+
+    async fn get_smt_values(
+        &self,
+        smt_name: &str,
+        keys: Vec<SmtKey>,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<Vec<SmtLeaf>, Error> {
+        info!(
+            "get smt values start: smt_name = {}, keys_len = {}, at = {}",
+            smt_name,
+            keys.len(),
+            generate_at_info(&at)
+        );
+        let db = self.read_db()?;
+        let tree_name = resolve_tree_name(&db, smt_name)?;
+        let snapshot = db.snapshot();
+        let rocksdb_store_smt = self.versioned_tree::<_, ()>(&db, &tree_name, &snapshot, &at)?;
+        // read the leaves directly from the store, `SparseMerkleTree::get` returns zero for absent keys
+        let rocksdb_store = rocksdb_store_smt.store();
+
+        let mut leaves = Vec::with_capacity(keys.len());
+        for key in keys {
+            let leaf_key: H256 = key.copy_new().into();
+            let value = match StoreReadOps::<SmtValue>::get_leaf(rocksdb_store, &leaf_key) {
+                Ok(v) => v,
                 Err(e) => {
-                    error!(
-                        "cannot initialize database store, smt_tree = {}, err = {}",
-                        smt_name, &e
+                    let err_str = format!(
+                        "cannot read leaf, smt_name = {}, key = {}, err = {}",
+                        smt_name,
+                        slice_to_hex_string(&key.0),
+                        e
                     );
-                    return Err(Error::Custom(e.to_string()));
+                    error!("{}", err_str);
+                    return Err(Error::Custom(err_str));
                 }
             };
+            leaves.push(SmtLeaf { key, value });
+        }
+
+        info!("get smt values end, leaves_len = {}", leaves.len());
+        Ok(leaves)
+    }
 
+    async fn get_smt_proof(
+        &self,
+        smt_name: &str,
+        keys_in: Vec<SmtKey>,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<ResponseProof, Error> {
+        info!(
+            "get smt proof start: smt_name = {}, keys_len = {}, at = {}",
+            smt_name,
+            keys_in.len(),
+            generate_at_info(&at)
+        );
+        let db = self.read_db()?;
+        let tree_name = resolve_tree_name(&db, smt_name)?;
+        let snapshot = db.snapshot();
+        let rocksdb_store_smt = self.versioned_tree::<_, ()>(&db, &tree_name, &snapshot, &at)?;
         let smt_root: SmtRoot = rocksdb_store_smt.root().into();
+
+        if keys_in.is_empty() {
+            warn!("empty keys in the request");
+            return Ok(ResponseProof {
+                root: smt_root,
+                ..Default::default()
+            });
+        }
+
+        let keys: Vec<H256> = keys_in.into_iter().map(|k| k.0.into()).collect();
+        let proofs = generate_proofs(&rocksdb_store_smt, &keys);
+        if proofs.len() != keys.len() {
+            let err_str = "some keys cannot generate proof";
+            error!("{}", err_str);
+            return Err(Error::Custom(err_str.to_string()));
+        }
+        let multi_proof = generate_multi_proof(&rocksdb_store_smt, keys)?;
+
+        let r = ResponseProof {
+            root: smt_root,
+            proofs: proofs.into_iter().collect(),
+            multi_proof,
+        };
         info!(
-            "get smt root end, root = {}",
-            slice_to_hex_string(&smt_root.0)
+            "get smt proof end, root = {}",
+            slice_to_hex_string(&r.root.0)
         );
-        Ok(smt_root)
+        Ok(r)
     }
 
-    async fn delete_smt(&self, smt_name: &str) -> Result<bool, Error> {
-        info!("delete smt tree {} start", &smt_name);
-        // OptimisticTransactionDB does not support delete_range, so we have to iterate all keys and update them to zero as a workaround
-        let snapshot = self.db.snapshot();
-        let prefix = smt_name.as_bytes();
-        let prefix_len = prefix.len();
-        let leaf_key_len = prefix_len + 32;
-        let kvs: Vec<(H256, SmtValue)> = snapshot
-            .iterator(IteratorMode::From(prefix, Direction::Forward))
-            .take_while(|(k, _)| k.starts_with(prefix))
-            .filter_map(|(k, _)| {
-                if k.len() != leaf_key_len {
-                    None
-                } else {
-                    match k[prefix_len..].try_into() {
-                        Ok(r) => {
-                            let leaf_key: [u8; 32] = r;
-                            Some((leaf_key.into(), SmtValue::zero()))
-                        }
-                        Err(e) => {
-                            warn!("cannot try into: {}", &e);
-                            None
-                        }
-                    }
+    async fn get_smt_non_membership_proof(
+        &self,
+        smt_name: &str,
+        key: SmtKey,
+        at: Option<SmtVersionQuery>,
+    ) -> Result<ResponseNonMembershipProof, Error> {
+        info!(
+            "get smt non-membership proof start: smt_name = {}, key = {}, at = {}",
+            smt_name,
+            slice_to_hex_string(&key.0),
+            generate_at_info(&at)
+        );
+        let db = self.read_db()?;
+        let tree_name = resolve_tree_name(&db, smt_name)?;
+        let snapshot = db.snapshot();
+        let rocksdb_store_smt = self.versioned_tree::<_, ()>(&db, &tree_name, &snapshot, &at)?;
+
+        let leaf_key: H256 = key.0.into();
+        let is_empty =
+            match StoreReadOps::<SmtValue>::get_leaf(rocksdb_store_smt.store(), &leaf_key) {
+                Ok(v) => v.is_none(),
+                Err(e) => {
+                    let err_str = format!(
+                        "cannot read leaf, smt_name = {}, key = {}, err = {}",
+                        smt_name,
+                        slice_to_hex_string(&key.0),
+                        e
+                    );
+                    error!("{}", err_str);
+                    return Err(Error::Custom(err_str));
                 }
+            };
+        if !is_empty {
+            warn!(
+                "key is not empty, the proof will not verify with a zero value, key = {}",
+                slice_to_hex_string(&key.0)
+            );
+        }
+
+        // the compiled proof only holds the siblings of the key, the zero value is supplied by the verifier
+        let proof = generate_multi_proof(&rocksdb_store_smt, vec![leaf_key])?;
+
+        let r = ResponseNonMembershipProof {
+            root: rocksdb_store_smt.root().into(),
+            proof,
+            is_empty,
+        };
+        info!(
+            "get smt non-membership proof end, root = {}, is_empty = {}",
+            slice_to_hex_string(&r.root.0),
+            r.is_empty
+        );
+        Ok(r)
+    }
+
+    async fn get_smt_versions(&self, smt_name: &str) -> Result<Vec<SmtVersion>, Error> {
+        info!("get smt versions of {}", smt_name);
+        let db = self.read_db()?;
+        let tree_name = resolve_tree_name(&db, smt_name)?;
+        let snapshot = db.snapshot();
+        let versions = match history::read_versions(&snapshot, &tree_name) {
+            Ok(versions) => versions,
+            Err(e) => {
+                error!("cannot read versions of smt {} : {}", smt_name, &e);
+                return Err(Error::Custom(e.to_string()));
+            }
+        };
+        info!("get smt versions end, versions_len = {}", versions.len());
+        Ok(versions
+            .into_iter()
+            .map(|r| SmtVersion {
+                version: r.version,
+                root: SmtRoot::from(&r.root),
+                tag: r.tag,
             })
-            .collect();
+            .collect())
+    }
 
-        let tx = self.db.transaction_default();
+    async fn rollback_smt(
+        &self,
+        smt_name: &str,
+        target: SmtVersionQuery,
+    ) -> Result<ResponseRollback, Error> {
+        let target = Some(target);
+        info!(
+            "rollback smt start: smt_name = {}, target = {}",
+            smt_name,
+            generate_at_info(&target)
+        );
+        let db = self.read_db()?;
+        let tree_name = resolve_tree_name(&db, smt_name)?;
+        // the commit fails if the tree is updated while it's rolled back
+        let tx = transaction(&db, true);
+        let record = match self.resolve_version(&tx, &tree_name, &target)? {
+            Some(record) => record,
+            None => {
+                let err_str = "the target of the rollback is not given".to_string();
+                error!("{}", err_str);
+                return Err(Error::Custom(err_str));
+            }
+        };
 
-        debug!("get handle of smt store");
-        let mut rocksdb_store_smt = match DefaultStoreMultiSMT::new_with_store(
-            DefaultStoreMultiTree::new(smt_name.as_bytes(), &tx),
-        ) {
-            Ok(r) => r,
+        let leaves = match history::rollback_leaves::<_, SmtValue>(&tx, &tree_name, record.version)
+        {
+            Ok(leaves) => leaves,
             Err(e) => {
-                error!("cannot initialize database store : {}", &e);
+                error!("cannot read undo records of smt {} : {}", smt_name, &e);
                 return Err(Error::Custom(e.to_string()));
             }
         };
+        let restored_leaves = leaves.len();
 
-        info!("delete start, keys num = {}", kvs.len());
-        let delete_chunk_size = CHUNK_SIZE;
-        for chunk in kvs.chunks(delete_chunk_size) {
-            let _ = match rocksdb_store_smt.update_all(chunk.to_vec()) {
-                Ok(_) => {}
+        let mut rocksdb_store_smt = self.tree(&db, &tree_name, &tx)?;
+        for chunk in leaves.chunks(CHUNK_SIZE) {
+            if let Err(e) = rocksdb_store_smt.update_all(chunk.to_vec()) {
+                error!("rollback smt {} failed! err = {}", smt_name, &e);
+                return Err(Error::Custom(e.to_string()));
+            }
+        }
+        // the transaction is dropped without commit, so nothing is written if the root is not the recorded one
+        if rocksdb_store_smt.root() != &record.root {
+            let err_str = format!(
+                "the root of smt {} after the rollback is {}, but the root of version {} is {}",
+                smt_name,
+                slice_to_hex_string(rocksdb_store_smt.root().as_slice()),
+                record.version,
+                slice_to_hex_string(record.root.as_slice())
+            );
+            error!("{}", err_str);
+            return Err(integrity_error(err_str));
+        }
+
+        let removed_versions = match history::truncate_versions(&tx, &tree_name, record.version) {
+            Ok(removed) => removed,
+            Err(e) => {
+                error!("cannot remove versions of smt {} : {}", smt_name, &e);
+                return Err(Error::Custom(e.to_string()));
+            }
+        };
+        commit_to_database(&tx)?;
+
+        let r = ResponseRollback {
+            root: SmtRoot::from(&record.root),
+            version: record.version,
+            restored_leaves,
+            removed_versions,
+        };
+        info!(
+            "rollback smt end, root = {}, version = {}, restored leaves num = {}, removed versions num = {}",
+            slice_to_hex_string(&r.root.0),
+            r.version,
+            r.restored_leaves,
+            r.removed_versions
+        );
+        Ok(r)
+    }
+
+    async fn verify_smt_proof(
+        &self,
+        root: SmtRoot,
+        proof: SmtProof,
+        kvs_in: Vec<Pair>,
+    ) -> Result<bool, Error> {
+        info!(
+            "verify smt proof start: root = {}, proof_len = {}, kvs_len = {}, {}",
+            slice_to_hex_string(&root.0),
+            proof.0.len(),
+            kvs_in.len(),
+            generate_kvs_info(&kvs_in)
+        );
+        debug!("{}", generate_kvs_debug(&kvs_in));
+
+        let leaves: Vec<(H256, H256)> = kvs_in
+            .into_iter()
+            .map(|k| (k.key.0.into(), k.value.to_h256()))
+            .collect();
+        let compiled_proof = CompiledMerkleProof(proof.0);
+        let root: H256 = root.0.into();
+
+        match compiled_proof.verify::<Blake2bHasherCustom>(&root, leaves) {
+            Ok(verified) => {
+                info!("verify smt proof end, verified = {}", verified);
+                Ok(verified)
+            }
+            Err(e) => {
+                let err_str = format!("malformed proof, {}", describe_proof_error(&e));
+                error!("{}", err_str);
+                Err(Error::Custom(err_str))
+            }
+        }
+    }
+
+    async fn delete_smt(&self, smt_name: &str) -> Result<bool, Error> {
+        info!("delete smt tree {} start", &smt_name);
+        let removed = self.purge_tree(&self.resolve_tree_name(smt_name)?)?;
+        info!(
+            "delete smt tree {} end, removed keys num = {}",
+            &smt_name, removed
+        );
+        Ok(true)
+    }
+
+    async fn purge_smt(&self, smt_name: &str) -> Result<usize, Error> {
+        info!("purge smt tree {} start", &smt_name);
+        let removed = self.purge_tree(&self.resolve_tree_name(smt_name)?)?;
+        info!(
+            "purge smt tree {} end, removed keys num = {}",
+            &smt_name, removed
+        );
+        Ok(removed)
+    }
+
+    async fn gc_smt(&self, smt_name: &str) -> Result<ResponseGc, Error> {
+        info!("gc smt tree {} start", &smt_name);
+        let response = self.collect_garbage(&self.resolve_tree_name(smt_name)?)?;
+        info!(
+            "gc smt tree {} end, removed keys num = {}, reclaimed bytes = {}",
+            &smt_name, response.removed_keys, response.reclaimed_bytes
+        );
+        Ok(response)
+    }
+
+    async fn reset_smt(&self, smt_name: &str) -> Result<u64, Error> {
+        info!("reset smt tree {} start", &smt_name);
+        let version = {
+            // no request reads or writes the tree while its version changes
+            let db = self.write_db()?;
+            let tx = db.transaction_default();
+            let version = match upgrade_smt_tree_version(&tx, smt_name) {
+                Ok(version) => version,
                 Err(e) => {
-                    error!("delete smt in database failed! : {}", &e);
+                    error!("cannot upgrade version of smt {} : {}", smt_name, &e);
                     return Err(Error::Custom(e.to_string()));
                 }
             };
             commit_to_database(&tx)?;
-        }
-
-        info!("delete smt tree {} end", &smt_name);
-
-        let smt_root = rocksdb_store_smt.root();
-        if smt_root.eq(&H256::zero()) {
-            info!("delete smt tree {}: success", &smt_name);
-        } else {
-            let err_str = format!("delete smt tree {}", smt_name);
-            error!("{}", err_str);
-            return Err(Error::Custom(err_str));
-        }
+            version
+        };
+        self.spawn_cleanup();
+        info!("reset smt tree {} end, version = {}", &smt_name, version);
+        Ok(version)
+    }
 
-        // let mut builder = ObjectParams::new();
-        // builder.insert("delete_result", true);
+    async fn copy_smt(&self, src: &str, dst: &str) -> Result<ResponseCopy, Error> {
+        info!("copy smt tree {} to {} start", src, dst);
+        let response = self.copy_tree(src, dst)?;
+        info!(
+            "copy smt tree {} to {} end, copied keys num = {}",
+            src, dst, response.copied_keys
+        );
+        Ok(response)
+    }
 
+    async fn swap_smt(&self, smt_name1: &str, smt_name2: &str) -> Result<bool, Error> {
+        info!("swap smt tree {} with {} start", smt_name1, smt_name2);
+        let (tree_name1, tree_name2) = {
+            // no request reads or writes either tree while they are swapped, so no request sees one tree swapped and
+            // the other not
+            let db = self.write_db()?;
+            let tx = db.transaction_default();
+            let tree_names = match swap_smt_trees(&tx, smt_name1, smt_name2) {
+                Ok(tree_names) => tree_names,
+                Err(e) => {
+                    error!("cannot swap smt {} with {} : {}", smt_name1, smt_name2, &e);
+                    return Err(Error::Custom(e.to_string()));
+                }
+            };
+            commit_to_database(&tx)?;
+            tree_names
+        };
+        info!(
+            "swap smt tree {} with {} end, {} = {}, {} = {}",
+            smt_name1,
+            smt_name2,
+            smt_name1,
+            tree_name_to_string(&tree_name1),
+            smt_name2,
+            tree_name_to_string(&tree_name2)
+        );
         Ok(true)
     }
 }
 
+// Create a transaction for an update, if the update is guarded by an expected root or recorded as a version the
+// transaction takes a snapshot when it begins, so its commit fails if another commit changes the tree (and thus the root)
+// in the meantime.
+fn transaction(db: &OptimisticTransactionDB, snapshot: bool) -> OptimisticTransaction {
+    if !snapshot {
+        return db.transaction_default();
+    }
+    let mut txn_options = OptimisticTransactionOptions::new();
+    txn_options.set_snapshot(true);
+    db.transaction(&WriteOptions::default(), &txn_options)
+}
+
 fn commit_to_database(tx: &OptimisticTransaction) -> Result<(), Error> {
     let _ = match tx.commit() {
         Ok(_) => {
@@ -526,6 +1592,211 @@ fn commit_to_database(tx: &OptimisticTransaction) -> Result<(), Error> {
     Ok(())
 }
 
+// the keys starting with `prefix` in the column family `col` of `snapshot`, `None` is the default column family
+fn range_keys<T>(
+    snapshot: &T,
+    col: Option<&ColumnFamily>,
+    prefix: &[u8],
+) -> Result<Vec<Box<[u8]>>, Error>
+where
+    T: Iterate + IterateCF,
+{
+    Ok(range_records(snapshot, col, prefix)?
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect())
+}
+
+// the records whose keys start with `prefix` in the column family `col` of `snapshot`
+fn range_records<T>(
+    snapshot: &T,
+    col: Option<&ColumnFamily>,
+    prefix: &[u8],
+) -> Result<Vec<(Box<[u8]>, Box<[u8]>)>, Error>
+where
+    T: Iterate + IterateCF,
+{
+    Ok(range_iterator(snapshot, col, prefix)?.collect())
+}
+
+// iterate the records whose keys start with `prefix` in the column family `col` of `snapshot`
+fn range_iterator<'a, T>(
+    snapshot: &'a T,
+    col: Option<&ColumnFamily>,
+    prefix: &'a [u8],
+) -> Result<impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a, Error>
+where
+    T: Iterate + IterateCF,
+{
+    let mode = IteratorMode::From(prefix, Direction::Forward);
+    let iter = match col {
+        None => snapshot.iterator(mode),
+        Some(col) => match snapshot.iterator_cf(col, mode) {
+            Ok(iter) => iter,
+            Err(e) => {
+                error!("cannot iterate keys : {}", &e);
+                return Err(Error::Custom(e.to_string()));
+            }
+        },
+    };
+    Ok(iter.take_while(move |(k, _)| k.starts_with(prefix)))
+}
+
+// the number of keys of the column family `col` estimated by rocksdb, 0 if it's not available
+fn estimated_keys_num(db: &OptimisticTransactionDB, col: &ColumnFamily) -> usize {
+    match db.property_int_value_cf(col, "rocksdb.estimate-num-keys") {
+        Ok(num) => num.unwrap_or_default() as usize,
+        Err(e) => {
+            warn!("cannot estimate the number of keys : {}", &e);
+            0
+        }
+    }
+}
+
+// delete the history of the tree `tree_name` in `tx`, returns the number of deleted keys
+fn delete_history(tx: &OptimisticTransaction, tree_name: &[u8]) -> Result<usize, Error> {
+    match history::delete_history(tx, tree_name) {
+        Ok(deleted) => Ok(deleted),
+        Err(e) => {
+            error!(
+                "cannot delete history of smt {} : {}",
+                tree_name_to_string(tree_name),
+                &e
+            );
+            Err(Error::Custom(e.to_string()))
+        }
+    }
+}
+
+// the name the records of the tree `smt_name` are currently stored under, see `get_smt_tree_name`
+fn resolve_tree_name(db: &OptimisticTransactionDB, smt_name: &str) -> Result<Vec<u8>, Error> {
+    match get_smt_tree_name(db, smt_name) {
+        Ok(tree_name) => Ok(tree_name),
+        Err(e) => {
+            error!("cannot read version of smt {} : {}", smt_name, &e);
+            Err(Error::Custom(e.to_string()))
+        }
+    }
+}
+
+fn rollback_database(tx: &OptimisticTransaction) -> Result<(), Error> {
+    match tx.rollback() {
+        Ok(_) => {
+            info!("database rollback success");
+            Ok(())
+        }
+        Err(e) => {
+            error!("database rollback failed : {}", &e);
+            Err(Error::Custom(e.to_string()))
+        }
+    }
+}
+
+// reject an update if the current root of the tree is not the expected one
+fn check_expected_root(
+    smt_name: &str,
+    root: &H256,
+    expected_root: &Option<SmtRoot>,
+) -> Result<(), Error> {
+    let expected_root = match expected_root {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    if root.as_slice() == expected_root.0.as_slice() {
+        return Ok(());
+    }
+    let err_str = format!(
+        "root mismatch, smt_name = {}, expected root = {}, current root = {}",
+        smt_name,
+        slice_to_hex_string(&expected_root.0),
+        slice_to_hex_string(root.as_slice())
+    );
+    error!("{}", err_str);
+    Err(root_mismatch_error(err_str))
+}
+
+// commit an update, a conflict with another commit means the root changed after it was checked
+fn commit_to_database_expecting_root(
+    tx: &OptimisticTransaction,
+    expected_root: &Option<SmtRoot>,
+) -> Result<(), Error> {
+    if expected_root.is_none() {
+        return commit_to_database(tx);
+    }
+    match tx.commit() {
+        Ok(_) => {
+            info!("database commit success");
+            Ok(())
+        }
+        Err(e) => {
+            let err_str = e.to_string();
+            error!("database commit failed : {}", &err_str);
+            if is_write_conflict(&e) {
+                Err(root_mismatch_error(format!(
+                    "root mismatch, the tree was updated concurrently, err = {}",
+                    err_str
+                )))
+            } else {
+                Err(Error::Custom(err_str))
+            }
+        }
+    }
+}
+
+// Whether the commit of an optimistic transaction failed because of another commit. Rocksdb reports a write conflict as
+// `Busy`, and as `TryAgain` if the memtables no longer hold enough history to check for conflicts. The binding only
+// exposes the message of the status, not its code.
+pub(crate) fn is_write_conflict(err: &rocksdb::Error) -> bool {
+    let err_str: &str = err.as_ref();
+    err_str.starts_with("Resource busy") || err_str.starts_with("Operation failed. Try again.")
+}
+
+fn root_mismatch_error(message: String) -> Error {
+    Error::Call(CallError::Custom(ErrorObject::owned(
+        ROOT_MISMATCH_ERROR_CODE,
+        message,
+        None::<()>,
+    )))
+}
+
+fn integrity_error(message: String) -> Error {
+    Error::Call(CallError::Custom(ErrorObject::owned(
+        INTEGRITY_ERROR_CODE,
+        message,
+        None::<()>,
+    )))
+}
+
+fn generate_expected_root_info(expected_root: &Option<SmtRoot>) -> String {
+    match expected_root {
+        Some(r) => slice_to_hex_string(&r.0),
+        None => "none".to_string(),
+    }
+}
+
+fn generate_version_query_info(at: &SmtVersionQuery) -> String {
+    let mut fields = Vec::new();
+    if let Some(version) = at.version {
+        fields.push(format!("version = {}", version));
+    }
+    if let Some(tag) = at.tag {
+        fields.push(format!("tag = {}", tag));
+    }
+    if let Some(root) = &at.root {
+        fields.push(format!("root = {}", slice_to_hex_string(&root.0)));
+    }
+    fields.join(", ")
+}
+
+fn generate_at_info(at: &Option<SmtVersionQuery>) -> String {
+    match at {
+        Some(at) if at.version.is_some() || at.tag.is_some() || at.root.is_some() => {
+            generate_version_query_info(at)
+        }
+        _ => "current".to_string(),
+    }
+}
+
 fn generate_pair_string(p: &Pair) -> String {
     format!(
         "{{ key = {}, value = {}}}",
@@ -574,9 +1845,10 @@ fn generate_response_debug(response: &Response) -> String {
             break;
         }
     }
+    let multi_proof_str = response.multi_proof.as_deref().unwrap_or_default();
     format!(
-        "response: {{ root = {}, proofs =  {},}}",
-        root_str, proofs_str
+        "response: {{ root = {}, proofs =  {}, multi_proof = {},}}",
+        root_str, proofs_str, multi_proof_str
     )
 }
 
@@ -602,12 +1874,145 @@ fn generate_response_sequence_debug(rs: &ResponseSequence) -> String {
         }
     }
 
+    let mut steps_str = String::new();
+    for step in rs.steps.iter().take(MAX_DISPLAY_NUMS + 1) {
+        steps_str.push_str(
+            format!(
+                "{{ index = {}, key = {}, root_before = {}, root_after = {}}},",
+                step.index,
+                slice_to_hex_string(&step.key.0),
+                slice_to_hex_string(&step.root_before.0),
+                slice_to_hex_string(&step.root_after.0)
+            )
+            .as_str(),
+        );
+    }
+
     format!(
-        "response: {{roots: {}, proofs: {},}}",
-        roots_str, proofs_str
+        "response: {{roots: {}, proofs: {}, steps: {},}}",
+        roots_str, proofs_str, steps_str
     )
 }
 
+// generate a compiled proof for each key, keys that fail to generate a proof are logged and skipped
+fn generate_proofs<S>(
+    smt: &SparseMerkleTree<Blake2bHasherCustom, SmtValue, S>,
+    keys: &[H256],
+) -> Vec<(String, String)>
+where
+    S: StoreReadOps<SmtValue> + Sync,
+{
+    keys.par_iter()
+        .filter_map(|k| {
+            let vec = vec![*k];
+            let proof = match smt.merkle_proof(vec.clone()) {
+                Ok(proof) => proof,
+                Err(e) => {
+                    error!(
+                        "get merkle proof failed!  key= {} : err = {}",
+                        slice_to_hex_string(k.as_slice()),
+                        &e
+                    );
+                    return None;
+                }
+            };
+            match proof.compile(vec) {
+                Ok(compiled_proof) => {
+                    let k = slice_to_hex_string(k.as_slice());
+                    let v = slice_to_hex_string(compiled_proof.0.as_slice());
+                    Some((k, v))
+                }
+                Err(e) => {
+                    error!("unable to generate compiled proof : {}", &e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// generate a single compiled proof covering all of the keys
+fn generate_multi_proof<S>(
+    smt: &SparseMerkleTree<Blake2bHasherCustom, SmtValue, S>,
+    mut keys: Vec<H256>,
+) -> Result<String, Error>
+where
+    S: StoreReadOps<SmtValue>,
+{
+    // a key can only appear once in a merkle proof
+    keys.sort_unstable();
+    keys.dedup();
+    let compiled_proof = smt.merkle_proof(keys.clone()).and_then(|p| p.compile(keys));
+    match compiled_proof {
+        Ok(cp) => Ok(slice_to_hex_string(cp.0.as_slice())),
+        Err(e) => {
+            let err_str = format!("cannot generate multi-key compiled proof, err = {}", e);
+            error!("{}", err_str);
+            Err(Error::Custom(err_str))
+        }
+    }
+}
+
+// explain why a compiled proof cannot be verified, the errors of sparse-merkle-tree are terse
+fn describe_proof_error(e: &SmtError) -> String {
+    match e {
+        SmtError::EmptyProof => "the proof is empty".to_string(),
+        SmtError::EmptyKeys => "no key-value pairs are given to verify".to_string(),
+        SmtError::CorruptedProof => {
+            "the proof is truncated or does not match the given keys".to_string()
+        }
+        SmtError::InvalidCode(code) => format!(
+            "the proof contains an unknown opcode 0x{:02x}, it may not be a compiled proof",
+            code
+        ),
+        SmtError::IncorrectNumberOfLeaves { expected, actual } => format!(
+            "the proof is built for {} keys but {} key-value pairs are given",
+            expected, actual
+        ),
+        _ => format!("err = {}", e),
+    }
+}
+
+// the root, the values of the keys and a compiled proof of those values against the root, the values of repeated keys
+// are only returned once
+fn generate_previous_state<S>(
+    smt: &SparseMerkleTree<Blake2bHasherCustom, SmtValue, S>,
+    keys: &[H256],
+) -> Result<(SmtRoot, Vec<Pair>, String), Error>
+where
+    S: StoreReadOps<SmtValue>,
+{
+    let root = SmtRoot::from(smt.root());
+    let mut values = Vec::with_capacity(keys.len());
+    let mut seen = HashSet::with_capacity(keys.len());
+    for k in keys {
+        if !seen.insert(*k) {
+            continue;
+        }
+        match smt.get(k) {
+            Ok(value) => values.push(Pair {
+                key: SmtKey::from(k),
+                value,
+            }),
+            Err(e) => {
+                let err_str = format!(
+                    "cannot read previous value, key = {}, err = {}",
+                    slice_to_hex_string(k.as_slice()),
+                    e
+                );
+                error!("{}", err_str);
+                return Err(Error::Custom(err_str));
+            }
+        }
+    }
+    let proof = if keys.is_empty() {
+        String::new()
+    } else {
+        generate_multi_proof(smt, keys.to_vec())?
+    };
+    Ok((root, values, proof))
+}
+
 fn default_merkel_proof() -> Vec<(String, String)> {
     let mut smt_proofs = Vec::new();
     let k = slice_to_hex_string(SmtKey::default().0.as_slice());
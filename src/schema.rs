@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Result};
+use rocksdb::{
+    prelude::{Get, GetColumnFamilys, Iterate, IterateCF, Put},
+    IteratorMode, OptimisticTransactionDB,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::blake2b::HASHER_ID;
+use crate::serde::{BranchNodeEncoding, KEY_LAYOUT};
+use crate::tree_store::{StoreLayout, BRANCH_CF, LEAF_CF};
+
+/// The first byte of the keys of the records describing the database itself, no tree record starts with it.
+pub const META_KEY_TAG: u8 = 0;
+
+const METADATA_KEY: &[u8] = b"\x00metadata";
+
+/// The version of the on-disk layout written by this build.
+///
+/// * 1: the records of a tree are prefixed with `tree_name_to_prefix` instead of the raw tree name
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Describes how the records of a database are serialized, it's written when the database is created and checked
+/// every time the database is opened.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub schema_version: u32,
+    // whether the database was written with the `trie` feature, which serializes branch nodes with additional tags
+    pub trie: bool,
+    // the hasher of the trees, see `HASHER_ID`
+    pub hasher: String,
+    // the layout of the keys of the trees, see `KEY_LAYOUT`
+    pub key_layout: String,
+    // the encoding of the branch nodes written to the database, the databases created before it was recorded use the
+    // full encoding
+    #[serde(default)]
+    pub branch_node_encoding: BranchNodeEncoding,
+    // the layout of the trees, the databases created before it was recorded use the default layout
+    #[serde(default)]
+    pub store_layout: StoreLayout,
+}
+
+impl Metadata {
+    /// The metadata of the databases written by this build.
+    pub fn current() -> Self {
+        Self::new(BranchNodeEncoding::default(), StoreLayout::default())
+    }
+
+    /// The metadata of the databases written by this build with the branch node encoding `branch_node_encoding` and the
+    /// store layout `store_layout`.
+    pub fn new(branch_node_encoding: BranchNodeEncoding, store_layout: StoreLayout) -> Self {
+        Metadata {
+            schema_version: SCHEMA_VERSION,
+            trie: cfg!(feature = "trie"),
+            hasher: HASHER_ID.to_string(),
+            key_layout: KEY_LAYOUT.to_string(),
+            branch_node_encoding,
+            store_layout,
+        }
+    }
+
+    /// Check the database described by this metadata can be read and written by this build, the branch node encoding
+    /// is not checked since both encodings are always readable.
+    pub fn check_compatible(&self) -> Result<()> {
+        let current = Metadata::current();
+        if self.schema_version != current.schema_version {
+            return Err(anyhow!(
+                "the database schema version is {}, but this build only supports version {}",
+                self.schema_version,
+                current.schema_version
+            ));
+        }
+        if self.trie != current.trie {
+            return Err(anyhow!(
+                "the database was written with the `trie` feature {}, but this build has it {}",
+                if self.trie { "enabled" } else { "disabled" },
+                if current.trie { "enabled" } else { "disabled" }
+            ));
+        }
+        if self.hasher != current.hasher {
+            return Err(anyhow!(
+                "the database was written with the hasher `{}`, but this build uses `{}`",
+                self.hasher,
+                current.hasher
+            ));
+        }
+        if self.key_layout != current.key_layout {
+            return Err(anyhow!(
+                "the database was written with the key layout `{}`, but this build uses `{}`",
+                self.key_layout,
+                current.key_layout
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Read the metadata of the database, `None` if the database was written before metadata was recorded.
+pub fn read_metadata(db: &OptimisticTransactionDB) -> Result<Option<Metadata>> {
+    match db.get(METADATA_KEY)? {
+        Some(v) => {
+            let metadata = serde_json::from_slice(v.as_ref())
+                .map_err(|e| anyhow!("invalid metadata record: {}", e))?;
+            Ok(Some(metadata))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Record the metadata in the database.
+pub fn write_metadata(db: &OptimisticTransactionDB, metadata: &Metadata) -> Result<()> {
+    db.put(METADATA_KEY, serde_json::to_vec(metadata)?)?;
+    Ok(())
+}
+
+/// Check that the database can be served by this build with the store layout `store_layout`, an empty database is
+/// initialized with the metadata of this build, `branch_node_encoding` and `store_layout`. The branch node encoding of
+/// an existing database is read from its metadata.
+pub fn check_metadata(
+    db: &OptimisticTransactionDB,
+    branch_node_encoding: BranchNodeEncoding,
+    store_layout: StoreLayout,
+) -> Result<Metadata> {
+    match read_metadata(db)? {
+        Some(metadata) => {
+            metadata.check_compatible()?;
+            if metadata.store_layout != store_layout {
+                return Err(anyhow!(
+                    "the database was created with the store layout {:?}, but it's opened with the store layout {:?}",
+                    metadata.store_layout,
+                    store_layout
+                ));
+            }
+            Ok(metadata)
+        }
+        None => {
+            if has_records(db)? {
+                return Err(anyhow!(
+                    "the database was written with the legacy layout, run `migrate` on it first"
+                ));
+            }
+            let metadata = Metadata::new(branch_node_encoding, store_layout);
+            write_metadata(db, &metadata)?;
+            Ok(metadata)
+        }
+    }
+}
+
+// whether the database holds any record, in the default column family or in the column families of the column family
+// layout, a database without metadata has no other column families
+fn has_records(db: &OptimisticTransactionDB) -> Result<bool> {
+    if db.iterator(IteratorMode::Start).next().is_some() {
+        return Ok(true);
+    }
+    for name in [BRANCH_CF, LEAF_CF] {
+        if let Some(col) = db.cf_handle(name) {
+            if db.iterator_cf(col, IteratorMode::Start)?.next().is_some() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
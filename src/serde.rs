@@ -1,6 +1,27 @@
-use std::convert::TryInto;
+use std::{fmt, str::FromStr};
 
-use sparse_merkle_tree::{merge::MergeValue, BranchKey, BranchNode};
+use serde::{Deserialize, Serialize};
+use sparse_merkle_tree::{error::Error, merge::MergeValue, BranchKey, BranchNode, H256};
+
+use crate::utils::slice_to_hex_string;
+
+/// The first byte of the keys of all the records stored by the multi-tree stores.
+pub const TREE_KEY_TAG: u8 = 1;
+
+/// Identifies the key layout of `tree_name_to_prefix` in the metadata of a database.
+pub const KEY_LAYOUT: &str = "tree-name-length-prefix";
+
+/// Serialize a tree name into the prefix of the keys of all the records of the tree in a multi-tree store.
+///
+/// The name is length-prefixed, so the key prefix of a tree is never a prefix of another tree's key prefix, e.g. the
+/// records of "tree10" are never found by iterating over the records of "tree1".
+pub fn tree_name_to_prefix(name: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(5 + name.len());
+    ret.extend_from_slice(&[TREE_KEY_TAG]);
+    ret.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    ret.extend_from_slice(name);
+    ret
+}
 
 /// Serialize a `BranchKey` into a `Vec<u8>` for use as a key in the key-value store.
 pub fn branch_key_to_vec(key: &BranchKey) -> Vec<u8> {
@@ -14,7 +35,7 @@ pub fn branch_key_to_vec(key: &BranchKey) -> Vec<u8> {
 pub fn branch_node_to_vec(node: &BranchNode) -> Vec<u8> {
     match (&node.left, &node.right) {
         (MergeValue::Value(left), MergeValue::Value(right)) => {
-            let mut ret = Vec::with_capacity(33);
+            let mut ret = Vec::with_capacity(65);
             ret.extend_from_slice(&[0]);
             ret.extend_from_slice(left.as_slice());
             ret.extend_from_slice(right.as_slice());
@@ -158,160 +179,350 @@ pub fn branch_node_to_vec(node: &BranchNode) -> Vec<u8> {
     }
 }
 
-/// Deserialize a `BranchNode` from a slice that was previously serialized with `branch_node_to_vec`.
-pub fn slice_to_branch_node(slice: &[u8]) -> BranchNode {
-    match slice[0] {
-        0 => {
-            let left: [u8; 32] = slice[1..33].try_into().expect("checked slice");
-            let right: [u8; 32] = slice[33..65].try_into().expect("checked slice");
-            BranchNode {
-                left: MergeValue::Value(left.into()),
-                right: MergeValue::Value(right.into()),
-            }
-        }
-        1 => {
-            let left: [u8; 32] = slice[1..33].try_into().expect("checked slice");
-            let base_node: [u8; 32] = slice[33..65].try_into().expect("checked slice");
-            let zero_bits: [u8; 32] = slice[65..97].try_into().expect("checked slice");
-            let zero_count = slice[97];
-            BranchNode {
-                left: MergeValue::Value(left.into()),
-                right: MergeValue::MergeWithZero {
-                    base_node: base_node.into(),
-                    zero_bits: zero_bits.into(),
-                    zero_count,
-                },
-            }
-        }
-        2 => {
-            let base_node: [u8; 32] = slice[1..33].try_into().expect("checked slice");
-            let zero_bits: [u8; 32] = slice[33..65].try_into().expect("checked slice");
-            let zero_count = slice[65];
-            let right: [u8; 32] = slice[66..98].try_into().expect("checked slice");
-            BranchNode {
-                left: MergeValue::MergeWithZero {
-                    base_node: base_node.into(),
-                    zero_bits: zero_bits.into(),
-                    zero_count,
-                },
-                right: MergeValue::Value(right.into()),
-            }
+/// The flag set in the tag of the branch nodes serialized with `branch_node_to_vec_compact`, the tags of
+/// `branch_node_to_vec` never have it set, so both encodings can be read from the same database.
+pub const COMPACT_TAG_FLAG: u8 = 0x80;
+
+/// The encoding of the branch nodes written to a database, it's chosen when the database is created and recorded in
+/// its metadata. Both encodings are always readable.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BranchNodeEncoding {
+    // `branch_node_to_vec`, fixed length records
+    Full,
+    // `branch_node_to_vec_compact`, the zero bytes of `zero_bits` are omitted
+    Compact,
+}
+
+impl Default for BranchNodeEncoding {
+    fn default() -> Self {
+        BranchNodeEncoding::Full
+    }
+}
+
+impl FromStr for BranchNodeEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(BranchNodeEncoding::Full),
+            "compact" => Ok(BranchNodeEncoding::Compact),
+            _ => Err(format!(
+                "unknown branch node encoding `{}`, expected `full` or `compact`",
+                s
+            )),
         }
-        3 => {
-            let l_base_node: [u8; 32] = slice[1..33].try_into().expect("checked slice");
-            let l_zero_bits: [u8; 32] = slice[33..65].try_into().expect("checked slice");
-            let l_zero_count = slice[65];
-            let r_base_node: [u8; 32] = slice[66..98].try_into().expect("checked slice");
-            let r_zero_bits: [u8; 32] = slice[98..130].try_into().expect("checked slice");
-            let r_zero_count = slice[130];
-            BranchNode {
-                left: MergeValue::MergeWithZero {
-                    base_node: l_base_node.into(),
-                    zero_bits: l_zero_bits.into(),
-                    zero_count: l_zero_count,
-                },
-                right: MergeValue::MergeWithZero {
-                    base_node: r_base_node.into(),
-                    zero_bits: r_zero_bits.into(),
-                    zero_count: r_zero_count,
-                },
-            }
+    }
+}
+
+impl BranchNodeEncoding {
+    /// Serialize a `BranchNode` with this encoding.
+    pub fn encode(&self, node: &BranchNode) -> Vec<u8> {
+        match self {
+            BranchNodeEncoding::Full => branch_node_to_vec(node),
+            BranchNodeEncoding::Compact => branch_node_to_vec_compact(node),
         }
+    }
+}
+
+// the tag of a `BranchNode` in `branch_node_to_vec`, which tells the variants of its children
+fn branch_node_tag(node: &BranchNode) -> u8 {
+    match (&node.left, &node.right) {
+        (MergeValue::Value(_), MergeValue::Value(_)) => 0,
+        (MergeValue::Value(_), MergeValue::MergeWithZero { .. }) => 1,
+        (MergeValue::MergeWithZero { .. }, MergeValue::Value(_)) => 2,
+        (MergeValue::MergeWithZero { .. }, MergeValue::MergeWithZero { .. }) => 3,
         #[cfg(feature = "trie")]
-        4 => {
-            let left: [u8; 32] = slice[1..33].try_into().expect("checked slice");
-            let key: [u8; 32] = slice[33..65].try_into().expect("checked slice");
-            let value: [u8; 32] = slice[65..97].try_into().expect("checked slice");
-            let height = slice[97];
-            BranchNode {
-                left: MergeValue::Value(left.into()),
-                right: MergeValue::ShortCut {
-                    key: key.into(),
-                    value: value.into(),
-                    height,
-                },
-            }
-        }
+        (MergeValue::Value(_), MergeValue::ShortCut { .. }) => 4,
         #[cfg(feature = "trie")]
-        5 => {
-            let key: [u8; 32] = slice[1..33].try_into().expect("checked slice");
-            let value: [u8; 32] = slice[33..65].try_into().expect("checked slice");
-            let height = slice[65];
-            let right: [u8; 32] = slice[66..98].try_into().expect("checked slice");
-            BranchNode {
-                left: MergeValue::ShortCut {
-                    key: key.into(),
-                    value: value.into(),
-                    height,
-                },
-                right: MergeValue::Value(right.into()),
-            }
-        }
+        (MergeValue::ShortCut { .. }, MergeValue::Value(_)) => 5,
         #[cfg(feature = "trie")]
-        6 => {
-            let l_key: [u8; 32] = slice[1..33].try_into().expect("checked slice");
-            let l_value: [u8; 32] = slice[33..65].try_into().expect("checked slice");
-            let l_height = slice[65];
-            let r_key: [u8; 32] = slice[66..98].try_into().expect("checked slice");
-            let r_value: [u8; 32] = slice[98..130].try_into().expect("checked slice");
-            let r_height = slice[130];
-            BranchNode {
-                left: MergeValue::ShortCut {
-                    key: l_key.into(),
-                    value: l_value.into(),
-                    height: l_height,
-                },
-                right: MergeValue::ShortCut {
-                    key: r_key.into(),
-                    value: r_value.into(),
-                    height: r_height,
-                },
-            }
+        (MergeValue::ShortCut { .. }, MergeValue::ShortCut { .. }) => 6,
+        #[cfg(feature = "trie")]
+        (MergeValue::MergeWithZero { .. }, MergeValue::ShortCut { .. }) => 7,
+        #[cfg(feature = "trie")]
+        (MergeValue::ShortCut { .. }, MergeValue::MergeWithZero { .. }) => 8,
+    }
+}
+
+fn push_compact_merge_value(ret: &mut Vec<u8>, value: &MergeValue) {
+    match value {
+        MergeValue::Value(v) => ret.extend_from_slice(v.as_slice()),
+        MergeValue::MergeWithZero {
+            base_node,
+            zero_bits,
+            zero_count,
+        } => {
+            ret.extend_from_slice(base_node.as_slice());
+            ret.extend_from_slice(&[*zero_count]);
+            // bit i of the mask is set if byte i of `zero_bits` is not zero, only those bytes follow the mask
+            let mask = zero_bits
+                .as_slice()
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| **b != 0)
+                .fold(0u32, |mask, (i, _)| mask | (1 << i));
+            ret.extend_from_slice(&mask.to_be_bytes());
+            ret.extend(zero_bits.as_slice().iter().filter(|b| **b != 0));
         }
         #[cfg(feature = "trie")]
-        7 => {
-            let base_node: [u8; 32] = slice[1..33].try_into().expect("checked slice");
-            let zero_bits: [u8; 32] = slice[33..65].try_into().expect("checked slice");
-            let zero_count = slice[65];
-            let key: [u8; 32] = slice[66..98].try_into().expect("checked slice");
-            let value: [u8; 32] = slice[98..130].try_into().expect("checked slice");
-            let height = slice[130];
-            BranchNode {
-                left: MergeValue::MergeWithZero {
-                    base_node: base_node.into(),
-                    zero_bits: zero_bits.into(),
-                    zero_count,
-                },
-                right: MergeValue::ShortCut {
-                    key: key.into(),
-                    value: value.into(),
-                    height,
-                },
+        MergeValue::ShortCut { key, value, height } => {
+            ret.extend_from_slice(key.as_slice());
+            ret.extend_from_slice(value.as_slice());
+            ret.extend_from_slice(&[*height]);
+        }
+    }
+}
+
+/// Serialize a `BranchNode` into a compact `Vec<u8>`: the tag of `branch_node_to_vec` with `COMPACT_TAG_FLAG` set,
+/// followed by the children, in which `zero_bits` is written as a 4-byte mask of its non-zero bytes followed by those
+/// bytes.
+pub fn branch_node_to_vec_compact(node: &BranchNode) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(131);
+    ret.extend_from_slice(&[COMPACT_TAG_FLAG | branch_node_tag(node)]);
+    push_compact_merge_value(&mut ret, &node.left);
+    push_compact_merge_value(&mut ret, &node.right);
+    ret
+}
+
+/// The error of deserializing a `BranchNode` from a record that was not written by `branch_node_to_vec`, e.g. a
+/// truncated or corrupted record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BranchNodeError {
+    // the record is empty
+    Empty,
+    // the tag is not written by any version of `branch_node_to_vec` or `branch_node_to_vec_compact`
+    BadTag(u8),
+    // the tag is only written with the `trie` feature enabled
+    FeatureDisabledTag(u8),
+    // the length of the record does not match its tag
+    WrongLength {
+        tag: u8,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for BranchNodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BranchNodeError::Empty => write!(f, "empty branch node"),
+            BranchNodeError::BadTag(tag) => write!(f, "unknown branch node tag {}", tag),
+            BranchNodeError::FeatureDisabledTag(tag) => {
+                write!(f, "branch node tag {} requires the `trie` feature", tag)
             }
+            BranchNodeError::WrongLength {
+                tag,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "branch node with tag {} should be {} bytes, got {} bytes",
+                tag, expected, actual
+            ),
         }
+    }
+}
+
+impl std::error::Error for BranchNodeError {}
+
+// the length of a `BranchNode` serialized by `branch_node_to_vec` with the given tag
+fn branch_node_len(tag: u8) -> Result<usize, BranchNodeError> {
+    match tag {
+        0 => Ok(65),
+        1 | 2 => Ok(98),
+        3 => Ok(131),
         #[cfg(feature = "trie")]
-        8 => {
-            let key: [u8; 32] = slice[1..33].try_into().expect("checked slice");
-            let value: [u8; 32] = slice[33..65].try_into().expect("checked slice");
-            let height = slice[65];
-            let base_node: [u8; 32] = slice[66..98].try_into().expect("checked slice");
-            let zero_bits: [u8; 32] = slice[98..130].try_into().expect("checked slice");
-            let zero_count = slice[130];
-            BranchNode {
-                left: MergeValue::ShortCut {
-                    key: key.into(),
-                    value: value.into(),
-                    height,
-                },
-                right: MergeValue::MergeWithZero {
-                    base_node: base_node.into(),
-                    zero_bits: zero_bits.into(),
-                    zero_count,
-                },
-            }
+        4 | 5 => Ok(98),
+        #[cfg(feature = "trie")]
+        6..=8 => Ok(131),
+        #[cfg(not(feature = "trie"))]
+        4..=8 => Err(BranchNodeError::FeatureDisabledTag(tag)),
+        _ => Err(BranchNodeError::BadTag(tag)),
+    }
+}
+
+// read the 32 bytes at `offset`, the length of the slice is checked by `try_slice_to_branch_node`
+fn h256_at(slice: &[u8], offset: usize) -> H256 {
+    let mut buf = [0u8; 32];
+    buf.copy_from_slice(&slice[offset..offset + 32]);
+    buf.into()
+}
+
+fn merge_with_zero_at(slice: &[u8], offset: usize) -> MergeValue {
+    MergeValue::MergeWithZero {
+        base_node: h256_at(slice, offset),
+        zero_bits: h256_at(slice, offset + 32),
+        zero_count: slice[offset + 64],
+    }
+}
+
+#[cfg(feature = "trie")]
+fn shortcut_at(slice: &[u8], offset: usize) -> MergeValue {
+    MergeValue::ShortCut {
+        key: h256_at(slice, offset),
+        value: h256_at(slice, offset + 32),
+        height: slice[offset + 64],
+    }
+}
+
+/// Deserialize a `BranchNode` from a slice that was previously serialized with `branch_node_to_vec` or
+/// `branch_node_to_vec_compact`, returns an error instead of panicking if the slice is truncated or corrupted.
+pub fn try_slice_to_branch_node(slice: &[u8]) -> Result<BranchNode, BranchNodeError> {
+    let tag = *slice.first().ok_or(BranchNodeError::Empty)?;
+    if tag & COMPACT_TAG_FLAG != 0 {
+        return try_compact_slice_to_branch_node(slice);
+    }
+    let expected = branch_node_len(tag)?;
+    if slice.len() != expected {
+        return Err(BranchNodeError::WrongLength {
+            tag,
+            expected,
+            actual: slice.len(),
+        });
+    }
+    let (left, right) = match tag {
+        0 => (
+            MergeValue::Value(h256_at(slice, 1)),
+            MergeValue::Value(h256_at(slice, 33)),
+        ),
+        1 => (
+            MergeValue::Value(h256_at(slice, 1)),
+            merge_with_zero_at(slice, 33),
+        ),
+        2 => (
+            merge_with_zero_at(slice, 1),
+            MergeValue::Value(h256_at(slice, 66)),
+        ),
+        3 => (merge_with_zero_at(slice, 1), merge_with_zero_at(slice, 66)),
+        #[cfg(feature = "trie")]
+        4 => (MergeValue::Value(h256_at(slice, 1)), shortcut_at(slice, 33)),
+        #[cfg(feature = "trie")]
+        5 => (shortcut_at(slice, 1), MergeValue::Value(h256_at(slice, 66))),
+        #[cfg(feature = "trie")]
+        6 => (shortcut_at(slice, 1), shortcut_at(slice, 66)),
+        #[cfg(feature = "trie")]
+        7 => (merge_with_zero_at(slice, 1), shortcut_at(slice, 66)),
+        #[cfg(feature = "trie")]
+        8 => (shortcut_at(slice, 1), merge_with_zero_at(slice, 66)),
+        _ => return Err(BranchNodeError::BadTag(tag)),
+    };
+    Ok(BranchNode { left, right })
+}
+
+/// Deserialize a `BranchNode` from a slice that was previously serialized with `branch_node_to_vec` or
+/// `branch_node_to_vec_compact`, panics if the slice is truncated or corrupted.
+#[deprecated(note = "use `try_slice_to_branch_node`, which returns an error instead of panicking")]
+pub fn slice_to_branch_node(slice: &[u8]) -> BranchNode {
+    try_slice_to_branch_node(slice).expect("invalid branch node")
+}
+
+// reads the children of a compact branch node, a child can not be read past the end of the slice
+struct CompactReader<'a> {
+    slice: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CompactReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BranchNodeError> {
+        if self.pos + len > self.slice.len() {
+            return Err(BranchNodeError::WrongLength {
+                tag: self.slice[0],
+                expected: self.pos + len,
+                actual: self.slice.len(),
+            });
         }
-        _ => {
-            unreachable!()
+        let ret = &self.slice[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(ret)
+    }
+
+    fn h256(&mut self) -> Result<H256, BranchNodeError> {
+        let mut buf = [0u8; 32];
+        buf.copy_from_slice(self.take(32)?);
+        Ok(buf.into())
+    }
+
+    fn byte(&mut self) -> Result<u8, BranchNodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn value(&mut self) -> Result<MergeValue, BranchNodeError> {
+        Ok(MergeValue::Value(self.h256()?))
+    }
+
+    fn merge_with_zero(&mut self) -> Result<MergeValue, BranchNodeError> {
+        let base_node = self.h256()?;
+        let zero_count = self.byte()?;
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(self.take(4)?);
+        let mask = u32::from_be_bytes(mask);
+        let mut zero_bits = [0u8; 32];
+        let non_zero_bytes = self.take(mask.count_ones() as usize)?;
+        for (i, b) in (0..32).filter(|i| mask & (1 << i) != 0).zip(non_zero_bytes) {
+            zero_bits[i] = *b;
         }
+        Ok(MergeValue::MergeWithZero {
+            base_node,
+            zero_bits: zero_bits.into(),
+            zero_count,
+        })
     }
+
+    #[cfg(feature = "trie")]
+    fn shortcut(&mut self) -> Result<MergeValue, BranchNodeError> {
+        Ok(MergeValue::ShortCut {
+            key: self.h256()?,
+            value: self.h256()?,
+            height: self.byte()?,
+        })
+    }
+}
+
+// deserialize a `BranchNode` serialized with `branch_node_to_vec_compact`
+fn try_compact_slice_to_branch_node(slice: &[u8]) -> Result<BranchNode, BranchNodeError> {
+    let tag = slice[0] & !COMPACT_TAG_FLAG;
+    // reject the tags that can't be read by this build before reading the children
+    branch_node_len(tag).map_err(|e| match e {
+        BranchNodeError::BadTag(_) => BranchNodeError::BadTag(slice[0]),
+        BranchNodeError::FeatureDisabledTag(_) => BranchNodeError::FeatureDisabledTag(slice[0]),
+        e => e,
+    })?;
+    let mut reader = CompactReader { slice, pos: 1 };
+    let (left, right) = match tag {
+        0 => (reader.value()?, reader.value()?),
+        1 => (reader.value()?, reader.merge_with_zero()?),
+        2 => (reader.merge_with_zero()?, reader.value()?),
+        3 => (reader.merge_with_zero()?, reader.merge_with_zero()?),
+        #[cfg(feature = "trie")]
+        4 => (reader.value()?, reader.shortcut()?),
+        #[cfg(feature = "trie")]
+        5 => (reader.shortcut()?, reader.value()?),
+        #[cfg(feature = "trie")]
+        6 => (reader.shortcut()?, reader.shortcut()?),
+        #[cfg(feature = "trie")]
+        7 => (reader.merge_with_zero()?, reader.shortcut()?),
+        #[cfg(feature = "trie")]
+        8 => (reader.shortcut()?, reader.merge_with_zero()?),
+        _ => return Err(BranchNodeError::BadTag(slice[0])),
+    };
+    if reader.pos != slice.len() {
+        return Err(BranchNodeError::WrongLength {
+            tag: slice[0],
+            expected: reader.pos,
+            actual: slice.len(),
+        });
+    }
+    Ok(BranchNode { left, right })
+}
+
+/// Deserialize the `BranchNode` stored under `key`, the error carries the key so the corrupted record can be found.
+pub fn record_to_branch_node(key: &[u8], value: &[u8]) -> Result<BranchNode, Error> {
+    try_slice_to_branch_node(value).map_err(|e| {
+        Error::Store(format!(
+            "corrupted branch node, key = {}: {}",
+            slice_to_hex_string(key),
+            e
+        ))
+    })
 }
@@ -1,5 +1,6 @@
 use crate::blake2b::Blake2bHasherCustom;
-use crate::default_store::DefaultStoreMultiTree;
+use crate::history::{JournalStore, VersionedStore};
+use crate::tree_store::TreeStore;
 use rocksdb::DBVector;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
@@ -7,13 +8,6 @@ use sparse_merkle_tree::default_store::DefaultStore;
 use sparse_merkle_tree::traits::Value;
 use sparse_merkle_tree::{SparseMerkleTree, H256};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-
-use lazy_static::lazy_static;
-
-lazy_static! {
-    pub static ref DBPATH: Arc<RwLock<String>> = Arc::new(RwLock::new(String::new()));
-}
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -38,11 +32,69 @@ pub struct Pair {
     pub(crate) value: SmtValue,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProofMode {
+    // a compiled proof for each key, returned in `Response::proofs`
+    PerKey,
+    // a single compiled proof of all the keys, returned in `Response::multi_proof`
+    Combined,
+    Both,
+}
+
+impl Default for ProofMode {
+    fn default() -> Self {
+        ProofMode::PerKey
+    }
+}
+
+impl ProofMode {
+    pub fn per_key(&self) -> bool {
+        matches!(self, ProofMode::PerKey | ProofMode::Both)
+    }
+
+    pub fn combined(&self) -> bool {
+        matches!(self, ProofMode::Combined | ProofMode::Both)
+    }
+}
+
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Opt {
     pub(crate) get_proof: bool,
     pub(crate) get_root: bool,
+    #[serde(default)]
+    pub(crate) proof_mode: ProofMode,
+    // commit the whole batch in a single transaction instead of one commit per chunk
+    #[serde(default = "default_atomic")]
+    pub(crate) atomic: bool,
+    // compute the roots and proofs of the update and roll it back instead of committing
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+    // return the root, the values and a proof of the values of the keys before the update
+    #[serde(default)]
+    pub(crate) get_previous: bool,
+    // recorded with the version of the update when the history is enabled, e.g. the block number of the update
+    #[serde(default)]
+    pub(crate) tag: Option<u64>,
+}
+
+fn default_atomic() -> bool {
+    true
+}
+
+impl Default for Opt {
+    fn default() -> Self {
+        Opt {
+            get_proof: false,
+            get_root: false,
+            proof_mode: ProofMode::default(),
+            atomic: default_atomic(),
+            dry_run: false,
+            get_previous: false,
+            tag: None,
+        }
+    }
 }
 
 #[serde_as]
@@ -50,19 +102,141 @@ pub struct Opt {
 pub struct Response {
     pub(crate) root: SmtRoot,
     pub(crate) proofs: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) multi_proof: Option<String>,
+    // the root before the update
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) previous_root: Option<SmtRoot>,
+    // the values of the keys before the update, zero for keys that were not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) previous_values: Option<Vec<Pair>>,
+    // a single compiled proof of `previous_values` against `previous_root`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) previous_proof: Option<String>,
+    // the version of the tree committed by the update, only if the history is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) version: Option<u64>,
 }
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResponseSequence {
+    // the maps are keyed by the hex encoded key, so only the last step of a repeated key is kept, see `steps`
     pub(crate) roots: HashMap<String, String>,
     pub(crate) proofs: HashMap<String, String>,
+    // one entry for each key-value pair of the request, in the order they are applied
+    pub(crate) steps: Vec<SequenceStep>,
+    // the version of the tree committed by the update, only if the history is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) version: Option<u64>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SequenceStep {
+    // the position of the key-value pair in the request
+    pub(crate) index: usize,
+    pub(crate) key: SmtKey,
+    pub(crate) value: SmtValue,
+    pub(crate) root_before: SmtRoot,
+    pub(crate) root_after: SmtRoot,
+    // compiled proof of the key, it verifies the old value against `root_before` as well as `value` against `root_after`
+    // since updating a key does not change its siblings, empty if proofs are not requested
+    pub(crate) proof: SmtProof,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ResponseProof {
+    pub(crate) root: SmtRoot,
+    // compiled proof of each key against `root`, keyed by the hex encoded key
+    pub(crate) proofs: HashMap<String, String>,
+    // a single compiled proof of all the keys against `root`
+    pub(crate) multi_proof: String,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ResponseNonMembershipProof {
+    pub(crate) root: SmtRoot,
+    // compiled proof of the key, verifies against `root` with a zero value if the key is empty
+    pub(crate) proof: String,
+    // whether the key is actually unset in the tree
+    pub(crate) is_empty: bool,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SmtLeaf {
+    pub(crate) key: SmtKey,
+    // `None` if no leaf is stored for the key, a key updated to zero is removed from the tree and reads as `None` too
+    pub(crate) value: Option<SmtValue>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResponseGc {
+    pub(crate) root: SmtRoot,
+    // the number of branch and leaf records not reachable from `root` which are deleted
+    pub(crate) removed_keys: usize,
+    // the total size of the keys and the values of the deleted records
+    pub(crate) reclaimed_bytes: u64,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SmtVersion {
+    pub(crate) version: u64,
+    pub(crate) root: SmtRoot,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tag: Option<u64>,
+}
+
+// Selects a retained version of a tree by its version, its tag or its root, the latest matching version is selected by
+// a tag or a root. The current state is read if no field is set.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SmtVersionQuery {
+    #[serde(default)]
+    pub(crate) version: Option<u64>,
+    #[serde(default)]
+    pub(crate) tag: Option<u64>,
+    #[serde(default)]
+    pub(crate) root: Option<SmtRoot>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResponseRollback {
+    // the root of the tree after the rollback, it's the root recorded for `version`
+    pub(crate) root: SmtRoot,
+    // the version the tree is rolled back to, it's the latest version of the tree now
+    pub(crate) version: u64,
+    // the number of leaves restored to their values at `version`
+    pub(crate) restored_leaves: usize,
+    // the number of versions newer than `version` which are removed
+    pub(crate) removed_versions: usize,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResponseCopy {
+    // the root of both trees
+    pub(crate) root: SmtRoot,
+    // the number of branch and leaf records copied
+    pub(crate) copied_keys: usize,
 }
 
 pub type MemoryStoreSMT = SparseMerkleTree<Blake2bHasherCustom, SmtValue, DefaultStore<SmtValue>>;
 
-pub(crate) type DefaultStoreMultiSMT<'a, T, W> =
-    SparseMerkleTree<Blake2bHasherCustom, SmtValue, DefaultStoreMultiTree<'a, T, W>>;
+pub(crate) type TreeStoreSMT<'a, T, W> =
+    SparseMerkleTree<Blake2bHasherCustom, SmtValue, TreeStore<'a, T, W>>;
+
+pub(crate) type JournalStoreSMT<'a, T, W> =
+    SparseMerkleTree<Blake2bHasherCustom, SmtValue, JournalStore<TreeStore<'a, T, W>>>;
+
+pub(crate) type VersionedStoreSMT<'a, T, W> =
+    SparseMerkleTree<Blake2bHasherCustom, SmtValue, VersionedStore<'a, T, TreeStore<'a, T, W>>>;
 
 impl From<&H256> for SmtKey {
     fn from(h: &H256) -> Self {
@@ -158,12 +332,3 @@ impl SmtKey {
         SmtKey(s)
     }
 }
-
-pub fn save_db_path(db_path: &String) {
-    let mut db = DBPATH.write().expect("cannot get lock");
-    db.push_str(db_path);
-}
-pub fn get_db_path() -> String {
-    let db = DBPATH.read().expect("cannot read lock");
-    String::from(db.as_str())
-}
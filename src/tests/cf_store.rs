@@ -1,10 +1,14 @@
 use rocksdb::{
-    prelude::{GetColumnFamilys, OpenCF},
-    OptimisticTransactionDB, Options, DB,
+    prelude::{CreateCF, DeleteCF, DropCF, GetColumnFamilys, IterateCF, OpenCF, PutCF},
+    IteratorMode, OptimisticTransactionDB, Options, DB,
 };
 use sparse_merkle_tree::{blake2b::Blake2bHasher, SparseMerkleTree, H256};
 
-use crate::cf_store::{ColumnFamilyStore, ColumnFamilyStoreMultiTree};
+use crate::cf_store::{
+    repair_stray_leaves, ColumnFamilyStore, ColumnFamilyStoreMultiTree, ColumnFamilyStorePerTree,
+};
+use crate::default_store::DefaultStoreMultiTree;
+use crate::tree_store::{tree_column_family_name, TreeStore, BRANCH_CF, LEAF_CF};
 
 use super::{new_blake2b, MemoryStoreSMT, Word};
 
@@ -168,3 +172,247 @@ fn test_multi_trees_store_functions() {
         assert_ne!(root_tree1, root_tree2);
     };
 }
+
+#[test]
+fn test_leaves_in_leaf_column_family() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let mut options = Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    let root = {
+        let db =
+            OptimisticTransactionDB::open_cf(&options, tmp_dir.path(), vec!["cf1", "cf2"]).unwrap();
+        let branch_col = db.cf_handle("cf1").unwrap();
+        let leaf_col = db.cf_handle("cf2").unwrap();
+        let tx = db.transaction_default();
+        let mut smt = ColumnFamilyStoreMultiSMT::new_with_store(ColumnFamilyStoreMultiTree::new(
+            b"tree1", &tx, branch_col, leaf_col,
+        ))
+        .unwrap();
+        smt.update_all(kvs.clone()).unwrap();
+        // remove a leaf, it must be removed from the leaf column family
+        smt.update(kvs[0].0, Word::default()).unwrap();
+        tx.commit().unwrap();
+        *smt.root()
+    };
+
+    // reopen the database and read the leaves back
+    let db =
+        OptimisticTransactionDB::open_cf(&options, tmp_dir.path(), vec!["cf1", "cf2"]).unwrap();
+    let branch_col = db.cf_handle("cf1").unwrap();
+    let leaf_col = db.cf_handle("cf2").unwrap();
+    let snapshot = db.snapshot();
+    let smt = ColumnFamilyStoreMultiSMT::new_with_store(ColumnFamilyStoreMultiTree::<_, ()>::new(
+        b"tree1", &snapshot, branch_col, leaf_col,
+    ))
+    .unwrap();
+    assert_eq!(smt.root(), &root);
+    assert_eq!(smt.get(&kvs[0].0).unwrap().0, "");
+    for (key, value) in kvs[1..].iter() {
+        assert_eq!(smt.get(key).unwrap().0, value.0);
+    }
+    let leaves_num = snapshot
+        .iterator_cf(leaf_col, IteratorMode::Start)
+        .unwrap()
+        .count();
+    assert_eq!(leaves_num, kvs.len() - 1);
+    assert_eq!(
+        repair_stray_leaves::<Blake2bHasher, Word>(&db, branch_col, leaf_col).unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_repair_stray_leaves() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let mut options = Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    let db =
+        OptimisticTransactionDB::open_cf(&options, tmp_dir.path(), vec!["cf1", "cf2"]).unwrap();
+    let branch_col = db.cf_handle("cf1").unwrap();
+    let leaf_col = db.cf_handle("cf2").unwrap();
+
+    let tx = db.transaction_default();
+    let mut smt = ColumnFamilyStoreMultiSMT::new_with_store(ColumnFamilyStoreMultiTree::new(
+        b"tree1", &tx, branch_col, leaf_col,
+    ))
+    .unwrap();
+    smt.update_all(kvs.clone()).unwrap();
+    tx.commit().unwrap();
+    let prefix = smt.store().prefix().to_vec();
+
+    // move the leaves to the branch column family, as the older versions of the store did
+    let leaves: Vec<(Box<[u8]>, Box<[u8]>)> = db
+        .iterator_cf(leaf_col, IteratorMode::Start)
+        .unwrap()
+        .collect();
+    let tx = db.transaction_default();
+    for (k, v) in leaves.iter() {
+        tx.put_cf(branch_col, k, v).unwrap();
+        tx.delete_cf(leaf_col, k).unwrap();
+    }
+    tx.commit().unwrap();
+    // a leaf written after the fix is kept
+    db.put_cf(
+        leaf_col,
+        &[prefix.as_slice(), kvs[0].0.as_slice()].concat(),
+        "newer",
+    )
+    .unwrap();
+
+    assert_eq!(
+        repair_stray_leaves::<Blake2bHasher, Word>(&db, branch_col, leaf_col).unwrap(),
+        kvs.len() - 1
+    );
+    let snapshot = db.snapshot();
+    let smt = ColumnFamilyStoreMultiSMT::new_with_store(ColumnFamilyStoreMultiTree::<_, ()>::new(
+        b"tree1", &snapshot, branch_col, leaf_col,
+    ))
+    .unwrap();
+    assert_eq!(smt.get(&kvs[0].0).unwrap().0, "newer");
+    for (key, value) in kvs[1..].iter() {
+        assert_eq!(smt.get(key).unwrap().0, value.0);
+    }
+    let branch_keys_len: Vec<usize> = snapshot
+        .iterator_cf(branch_col, IteratorMode::Start)
+        .unwrap()
+        .map(|(k, _)| k.len())
+        .collect();
+    assert!(branch_keys_len.iter().all(|len| *len == prefix.len() + 33));
+}
+
+type TreeStoreSMT<'a, T, W> = SparseMerkleTree<Blake2bHasher, Word, TreeStore<'a, T, W>>;
+
+#[test]
+fn test_tree_store_layouts() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let mut options = Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    let db = OptimisticTransactionDB::open_cf(&options, tmp_dir.path(), vec![BRANCH_CF, LEAF_CF])
+        .unwrap();
+    let branch_col = db.cf_handle(BRANCH_CF).unwrap();
+    let leaf_col = db.cf_handle(LEAF_CF).unwrap();
+
+    let tx = db.transaction_default();
+    let mut default_smt = TreeStoreSMT::new_with_store(TreeStore::Default(
+        DefaultStoreMultiTree::new(b"tree1", &tx),
+    ))
+    .unwrap();
+    let mut cf_smt = TreeStoreSMT::new_with_store(TreeStore::ColumnFamily(
+        ColumnFamilyStoreMultiTree::new(b"tree1", &tx, branch_col, leaf_col),
+    ))
+    .unwrap();
+    default_smt.update_all(kvs.clone()).unwrap();
+    cf_smt.update_all(kvs.clone()).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(default_smt.root(), cf_smt.root());
+
+    // the trees of the two layouts are stored apart
+    let snapshot = db.snapshot();
+    let cf_smt = TreeStoreSMT::new_with_store(TreeStore::ColumnFamily(
+        ColumnFamilyStoreMultiTree::<_, ()>::new(b"tree1", &snapshot, branch_col, leaf_col),
+    ))
+    .unwrap();
+    for (key, value) in kvs.iter() {
+        assert_eq!(cf_smt.get(key).unwrap().0, value.0);
+    }
+    let leaves_num = snapshot
+        .iterator_cf(leaf_col, IteratorMode::Start)
+        .unwrap()
+        .count();
+    assert_eq!(leaves_num, kvs.len());
+}
+
+#[test]
+fn test_tree_column_family_store() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let mut memory_store_smt = MemoryStoreSMT::new_with_store(Default::default()).unwrap();
+    memory_store_smt.update_all(kvs.clone()).unwrap();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let mut db = OptimisticTransactionDB::open_default(tmp_dir.path()).unwrap();
+    let cf1 = tree_column_family_name(b"tree1");
+    let cf2 = tree_column_family_name(b"tree2");
+    db.create_cf(&cf1, &Options::default()).unwrap();
+    db.create_cf(&cf2, &Options::default()).unwrap();
+    {
+        let col1 = db.cf_handle(&cf1).unwrap();
+        let col2 = db.cf_handle(&cf2).unwrap();
+        let tx = db.transaction_default();
+        let mut smt1 = TreeStoreSMT::new_with_store(TreeStore::TreeColumnFamily(
+            ColumnFamilyStorePerTree::new(&tx, col1),
+        ))
+        .unwrap();
+        let mut smt2 = TreeStoreSMT::new_with_store(TreeStore::TreeColumnFamily(
+            ColumnFamilyStorePerTree::new(&tx, col2),
+        ))
+        .unwrap();
+        smt1.update_all(kvs.clone()).unwrap();
+        smt2.update_all(kvs[..4].to_vec()).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(smt1.root(), memory_store_smt.root());
+        assert_ne!(smt1.root(), smt2.root());
+    }
+
+    // deleting a tree is dropping its column family, the other trees are untouched
+    db.drop_cf(&cf2).unwrap();
+    assert!(db.cf_handle(&cf2).is_none());
+    let snapshot = db.snapshot();
+    let smt1 = TreeStoreSMT::new_with_store(TreeStore::TreeColumnFamily(
+        ColumnFamilyStorePerTree::<_, ()>::new(&snapshot, db.cf_handle(&cf1).unwrap()),
+    ))
+    .unwrap();
+    assert_eq!(smt1.root(), memory_store_smt.root());
+    let smt2 =
+        TreeStoreSMT::new_with_store(TreeStore::<OptimisticTransactionDB, ()>::Absent).unwrap();
+    assert!(smt2.root().is_zero());
+}
@@ -1,7 +1,17 @@
-use rocksdb::{prelude::Open, OptimisticTransactionDB, DB};
-use sparse_merkle_tree::{blake2b::Blake2bHasher, SparseMerkleTree, H256};
+use rocksdb::{
+    prelude::{Iterate, Open, Put},
+    Direction, IteratorMode, OptimisticTransactionDB, DB,
+};
+use sparse_merkle_tree::{
+    blake2b::Blake2bHasher, error::Error, traits::Value, BranchKey, SparseMerkleTree, H256,
+};
 
 use crate::default_store::{DefaultStore, DefaultStoreMultiTree};
+use crate::migration::migrate_legacy_tree;
+use crate::serde::{
+    branch_key_to_vec, branch_node_to_vec, branch_node_to_vec_compact, try_slice_to_branch_node,
+    BranchNodeEncoding, BranchNodeError,
+};
 
 use super::{new_blake2b, MemoryStoreSMT, Word};
 
@@ -143,3 +153,251 @@ fn test_multi_trees_store_functions() {
         assert_ne!(root_tree1, root_tree2);
     };
 }
+
+#[test]
+fn test_multi_trees_prefix_isolation() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+    // "tree1" is a prefix of "tree10", their records must not be mixed up
+    let mut smt1 =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"tree1", &db)).unwrap();
+    let mut smt10 =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"tree10", &db)).unwrap();
+    smt1.update_all(kvs[..4].to_vec()).unwrap();
+    smt10.update_all(kvs[4..].to_vec()).unwrap();
+
+    let store1 = DefaultStoreMultiTree::<_, ()>::new(b"tree1", &db);
+    let prefix = store1.prefix().to_vec();
+    let leaves_num = db
+        .iterator(IteratorMode::From(&prefix, Direction::Forward))
+        .take_while(|(k, _)| k.starts_with(&prefix))
+        .filter(|(k, _)| k.len() == prefix.len() + 32)
+        .count();
+    assert_eq!(leaves_num, 4);
+
+    let mut memory_store_smt = MemoryStoreSMT::new_with_store(Default::default()).unwrap();
+    memory_store_smt.update_all(kvs[..4].to_vec()).unwrap();
+    let snapshot = db.snapshot();
+    let smt1 = DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::<_, ()>::new(
+        b"tree1", &snapshot,
+    ))
+    .unwrap();
+    assert_eq!(memory_store_smt.root(), smt1.root());
+}
+
+#[test]
+fn test_multi_trees_copy() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+    let mut src =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"src", &db)).unwrap();
+    src.update_all(kvs.clone()).unwrap();
+
+    // the records of a tree don't depend on its prefix, so a copy under another prefix has the same root
+    let src_prefix = DefaultStoreMultiTree::<_, ()>::new(b"src", &db)
+        .prefix()
+        .to_vec();
+    let dst_prefix = DefaultStoreMultiTree::<_, ()>::new(b"dst", &db)
+        .prefix()
+        .to_vec();
+    let records = db
+        .iterator(IteratorMode::From(&src_prefix, Direction::Forward))
+        .take_while(|(k, _)| k.starts_with(&src_prefix))
+        .collect::<Vec<_>>();
+    for (k, v) in records.iter() {
+        db.put([dst_prefix.as_slice(), &k[src_prefix.len()..]].concat(), v)
+            .unwrap();
+    }
+
+    let mut dst =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"dst", &db)).unwrap();
+    assert_eq!(src.root(), dst.root());
+    assert_eq!(dst.get(&kvs[1].0).unwrap().0, kvs[1].1 .0);
+
+    // the copy is updated independently of the source
+    dst.update(kvs[0].0, Word::zero()).unwrap();
+    assert_ne!(src.root(), dst.root());
+    let src =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"src", &db)).unwrap();
+    assert_eq!(src.get(&kvs[0].0).unwrap().0, kvs[0].1 .0);
+}
+
+#[test]
+fn test_corrupted_branch_node() {
+    assert_eq!(try_slice_to_branch_node(&[]), Err(BranchNodeError::Empty));
+    assert_eq!(
+        try_slice_to_branch_node(&[9; 65]),
+        Err(BranchNodeError::BadTag(9))
+    );
+    assert_eq!(
+        try_slice_to_branch_node(&[0; 64]),
+        Err(BranchNodeError::WrongLength {
+            tag: 0,
+            expected: 65,
+            actual: 64
+        })
+    );
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+    let mut smt =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"tree1", &db)).unwrap();
+    smt.update([1u8; 32].into(), Word("value".to_string()))
+        .unwrap();
+
+    // truncate the root branch node
+    let store = DefaultStoreMultiTree::<_, ()>::new(b"tree1", &db);
+    let root_key = [
+        store.prefix(),
+        &branch_key_to_vec(&BranchKey::new(u8::MAX, H256::zero())),
+    ]
+    .concat();
+    db.put(&root_key, &[1, 2, 3]).unwrap();
+
+    let smt = DefaultStoreMultiSMT::new_with_store(store);
+    match smt {
+        Err(Error::Store(msg)) => {
+            assert!(msg.contains(&crate::utils::slice_to_hex_string(&root_key)))
+        }
+        _ => panic!("corrupted branch node should be a store error"),
+    }
+}
+
+#[test]
+fn test_compact_branch_node_encoding() {
+    let kvs = (0..100u32)
+        .map(|i| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&i.to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(i.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let build = |encoding: BranchNodeEncoding| {
+        let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+        let db = DB::open_default(tmp_dir.path()).unwrap();
+        let mut smt = DefaultStoreMultiSMT::new_with_store(
+            DefaultStoreMultiTree::new(b"tree1", &db).with_encoding(encoding),
+        )
+        .unwrap();
+        smt.update_all(kvs.clone()).unwrap();
+        let root = *smt.root();
+        let proof = smt
+            .merkle_proof(vec![kvs[0].0])
+            .unwrap()
+            .compile(vec![kvs[0].0])
+            .unwrap();
+        // the branch records are the records which are not 32-byte leaf keys after the prefix
+        let prefix = DefaultStoreMultiTree::<_, ()>::new(b"tree1", &db)
+            .prefix()
+            .to_vec();
+        let branches: Vec<Box<[u8]>> = db
+            .iterator(IteratorMode::From(&prefix, Direction::Forward))
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter(|(k, _)| k.len() == prefix.len() + 33)
+            .map(|(_, v)| v)
+            .collect();
+        (root, proof.0, branches)
+    };
+    let (full_root, full_proof, full_branches) = build(BranchNodeEncoding::Full);
+    let (compact_root, compact_proof, compact_branches) = build(BranchNodeEncoding::Compact);
+    assert_eq!(full_root, compact_root);
+    assert_eq!(full_proof, compact_proof);
+    assert_eq!(full_branches.len(), compact_branches.len());
+    assert!(
+        compact_branches.iter().map(|v| v.len()).sum::<usize>()
+            < full_branches.iter().map(|v| v.len()).sum::<usize>()
+    );
+
+    // both encodings decode to the same nodes
+    for (full, compact) in full_branches.iter().zip(compact_branches.iter()) {
+        let node = try_slice_to_branch_node(full).unwrap();
+        assert_eq!(&branch_node_to_vec_compact(&node)[..], &compact[..]);
+        let compact_node = try_slice_to_branch_node(compact).unwrap();
+        assert_eq!(&branch_node_to_vec(&compact_node)[..], &full[..]);
+        // truncated compact records are rejected
+        assert!(try_slice_to_branch_node(&compact[..compact.len() - 1]).is_err());
+    }
+}
+
+#[test]
+fn test_migrate_legacy_tree() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = OptimisticTransactionDB::open_default(tmp_dir.path()).unwrap();
+
+    // write two trees whose records share a prefix with the legacy layout
+    let (root1, root10) = {
+        let tx = db.transaction_default();
+        let mut smt1 = DefaultStoreMultiSMT::new_with_store(
+            DefaultStoreMultiTree::new_with_raw_prefix(b"tree1", &tx),
+        )
+        .unwrap();
+        let mut smt10 = DefaultStoreMultiSMT::new_with_store(
+            DefaultStoreMultiTree::new_with_raw_prefix(b"tree10", &tx),
+        )
+        .unwrap();
+        smt1.update_all(kvs[..4].to_vec()).unwrap();
+        smt10.update_all(kvs[4..].to_vec()).unwrap();
+        tx.commit().unwrap();
+        (smt1.root().clone(), smt10.root().clone())
+    };
+
+    let migrated_root1 = migrate_legacy_tree::<Blake2bHasher, Word>(&db, b"tree1").unwrap();
+    let migrated_root10 = migrate_legacy_tree::<Blake2bHasher, Word>(&db, b"tree10").unwrap();
+    assert_eq!(root1, migrated_root1);
+    assert_eq!(root10, migrated_root10);
+    // migrating again is a no-op
+    assert_eq!(
+        root1,
+        migrate_legacy_tree::<Blake2bHasher, Word>(&db, b"tree1").unwrap()
+    );
+
+    let snapshot = db.snapshot();
+    let smt1 = DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::<_, ()>::new(
+        b"tree1", &snapshot,
+    ))
+    .unwrap();
+    let proof = smt1.merkle_proof(vec![kvs[0].0.clone()]).unwrap();
+    assert_eq!(&root1, smt1.root());
+    assert!(proof
+        .verify::<Blake2bHasher>(&root1, vec![(kvs[0].0.clone(), kvs[0].1.to_h256())])
+        .unwrap());
+}
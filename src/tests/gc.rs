@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use rocksdb::{
+    prelude::{Iterate, Open, Put},
+    Direction, IteratorMode, DB,
+};
+use sparse_merkle_tree::{blake2b::Blake2bHasher, BranchKey, SparseMerkleTree, H256};
+
+use crate::default_store::DefaultStoreMultiTree;
+use crate::gc::reachable_keys;
+use crate::serde::{branch_key_to_vec, tree_name_to_prefix};
+
+use super::{new_blake2b, Word};
+
+type DefaultStoreMultiSMT<'a, T, W> =
+    SparseMerkleTree<Blake2bHasher, Word, DefaultStoreMultiTree<'a, T, W>>;
+
+#[test]
+fn test_reachable_keys() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+    let mut smt =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"tree1", &db)).unwrap();
+    for (key, value) in kvs.iter() {
+        smt.update(key.clone(), value.clone()).unwrap();
+    }
+
+    // a branch record no longer reachable from the root, e.g. left behind by an interrupted commit
+    let prefix = tree_name_to_prefix(b"tree1");
+    let stray_key = branch_key_to_vec(&BranchKey::new(3, [0x5au8; 32].into()));
+    let (stored_key, stored_value) = db
+        .iterator(IteratorMode::From(&prefix, Direction::Forward))
+        .find(|(k, _)| k.len() == prefix.len() + 33)
+        .unwrap();
+    db.put(
+        [prefix.as_slice(), stray_key.as_slice()].concat(),
+        stored_value,
+    )
+    .unwrap();
+
+    let branch_keys: Vec<Vec<u8>> = db
+        .iterator(IteratorMode::From(&prefix, Direction::Forward))
+        .take_while(|(k, _)| k.starts_with(&prefix))
+        .filter(|(k, _)| k.len() == prefix.len() + 33)
+        .map(|(k, _)| k[prefix.len()..].to_vec())
+        .collect();
+    let snapshot = db.snapshot();
+    let store = DefaultStoreMultiTree::<_, ()>::new(b"tree1", &snapshot);
+    let reachable = reachable_keys::<Word, _>(&store).unwrap();
+
+    assert!(reachable.contains(&stored_key[prefix.len()..]));
+    assert!(!reachable.contains(&stray_key));
+    for branch_key in branch_keys.iter() {
+        if branch_key != &stray_key {
+            assert!(reachable.contains(branch_key));
+        }
+    }
+    for (key, _) in kvs.iter() {
+        assert!(reachable.contains(key.as_slice()));
+    }
+}
+
+#[test]
+fn test_orphaned_leaf() {
+    // the first two keys fork at the height 5, the third one is on the other side of the root, removing the first
+    // key removes the branch nodes only on its path, with or without the `trie` feature
+    let kvs = [0x01u8, 0x21, 0x80]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| {
+            let mut key = [0u8; 32];
+            if *byte == 0x80 {
+                key[31] = *byte;
+            } else {
+                key[0] = *byte;
+            }
+            (key.into(), Word(i.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+    let prefix = tree_name_to_prefix(b"tree1");
+    let records = || -> HashMap<Box<[u8]>, Box<[u8]>> {
+        db.iterator(IteratorMode::From(&prefix, Direction::Forward))
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .collect()
+    };
+    let mut smt =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"tree1", &db)).unwrap();
+    smt.update_all(kvs.clone()).unwrap();
+    let before = records();
+
+    // remove a leaf, then write back the records removed with it, as an interrupted commit could leave them behind
+    smt.update(kvs[0].0, Word::default()).unwrap();
+    let after = records();
+    let orphaned: Vec<(&Box<[u8]>, &Box<[u8]>)> = before
+        .iter()
+        .filter(|(k, _)| !after.contains_key(*k))
+        .collect();
+    assert!(orphaned.iter().any(|(k, _)| k.len() == prefix.len() + 32));
+    assert!(orphaned.iter().any(|(k, _)| k.len() == prefix.len() + 33));
+    for (k, v) in orphaned.iter() {
+        db.put(k, v).unwrap();
+    }
+
+    // the root no longer refers to the orphaned leaf, so neither it nor its branches are reachable
+    let snapshot = db.snapshot();
+    let store = DefaultStoreMultiTree::<_, ()>::new(b"tree1", &snapshot);
+    let reachable = reachable_keys::<Word, _>(&store).unwrap();
+    for (k, _) in orphaned.iter() {
+        assert!(!reachable.contains(&k[prefix.len()..]));
+    }
+    for k in after.keys() {
+        assert!(reachable.contains(&k[prefix.len()..]));
+    }
+    assert_eq!(reachable.len(), after.len());
+}
+
+#[cfg(feature = "trie")]
+#[test]
+fn test_reachable_shortcut() {
+    let key1: H256 = [1u8; 32].into();
+    let mut key2 = [1u8; 32];
+    key2[0] = 0x21;
+    let key2: H256 = key2.into();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+    let mut smt =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"tree1", &db)).unwrap();
+    let root_branch_key = branch_key_to_vec(&BranchKey::new(u8::MAX, H256::zero()));
+
+    // a single leaf is a shortcut of the root branch node, no branch node is stored below it
+    smt.update(key1, Word("a".to_string())).unwrap();
+    let snapshot = db.snapshot();
+    let store = DefaultStoreMultiTree::<_, ()>::new(b"tree1", &snapshot);
+    let reachable = reachable_keys::<Word, _>(&store).unwrap();
+    assert!(reachable.contains(&root_branch_key));
+    assert!(reachable.contains(key1.as_slice()));
+    assert_eq!(reachable.len(), 2);
+
+    // the leaves forking at the height 5 are shortcuts of the branch node there, above it the branch nodes are
+    // stored all the way up to the root
+    smt.update(key2, Word("b".to_string())).unwrap();
+    let snapshot = db.snapshot();
+    let store = DefaultStoreMultiTree::<_, ()>::new(b"tree1", &snapshot);
+    let reachable = reachable_keys::<Word, _>(&store).unwrap();
+    assert!(reachable.contains(&root_branch_key));
+    assert!(reachable.contains(&branch_key_to_vec(&BranchKey::new(5, key1.parent_path(5)))));
+    assert!(!reachable.contains(&branch_key_to_vec(&BranchKey::new(4, key1.parent_path(4)))));
+    assert!(reachable.contains(key1.as_slice()));
+    assert!(reachable.contains(key2.as_slice()));
+    assert_eq!(reachable.len(), 251 + 2);
+}
@@ -0,0 +1,169 @@
+use rocksdb::{prelude::Open, DB};
+use sparse_merkle_tree::{blake2b::Blake2bHasher, traits::StoreReadOps, SparseMerkleTree, H256};
+
+use crate::default_store::DefaultStoreMultiTree;
+use crate::history::{
+    read_versions, record_version, rollback_leaves, truncate_versions, JournalStore, VersionedStore,
+};
+
+use super::Word;
+
+type JournalStoreSMT<'a, T, W> =
+    SparseMerkleTree<Blake2bHasher, Word, JournalStore<DefaultStoreMultiTree<'a, T, W>>>;
+type DefaultStoreMultiSMT<'a, T, W> =
+    SparseMerkleTree<Blake2bHasher, Word, DefaultStoreMultiTree<'a, T, W>>;
+type VersionedStoreSMT<'a, T, W> =
+    SparseMerkleTree<Blake2bHasher, Word, VersionedStore<'a, T, DefaultStoreMultiTree<'a, T, W>>>;
+
+#[test]
+fn test_versioned_reads() {
+    let key1: H256 = [1u8; 32].into();
+    let key2: H256 = [2u8; 32].into();
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+
+    let updates = vec![
+        vec![(key1, Word("a".to_string()))],
+        vec![(key1, Word("b".to_string())), (key2, Word("c".to_string()))],
+        vec![(key2, Word::default())],
+    ];
+    let mut roots = Vec::new();
+    for (i, kvs) in updates.into_iter().enumerate() {
+        let store = JournalStore::new(DefaultStoreMultiTree::new(b"tree1", &db), true);
+        let mut smt = JournalStoreSMT::new_with_store(store).unwrap();
+        smt.update_all(kvs).unwrap();
+        let version = record_version(
+            &db,
+            b"tree1",
+            smt.root(),
+            Some(100 + i as u64),
+            smt.store().changes(),
+            10,
+        )
+        .unwrap();
+        assert_eq!(version, i as u64 + 1);
+        roots.push(*smt.root());
+    }
+
+    let snapshot = db.snapshot();
+    let versions = read_versions(&snapshot, b"tree1").unwrap();
+    assert_eq!(versions.len(), 3);
+    assert_eq!(versions[1].tag, Some(101));
+    for (i, root) in roots.iter().enumerate() {
+        assert_eq!(&versions[i].root, root);
+        let store = VersionedStore::new(
+            &snapshot,
+            b"tree1",
+            Some(i as u64 + 1),
+            DefaultStoreMultiTree::<_, ()>::new(b"tree1", &snapshot),
+        );
+        let smt = VersionedStoreSMT::new_with_store(store).unwrap();
+        assert_eq!(smt.root(), root);
+    }
+
+    let store = VersionedStore::new(
+        &snapshot,
+        b"tree1",
+        Some(1),
+        DefaultStoreMultiTree::<_, ()>::new(b"tree1", &snapshot),
+    );
+    let leaf: Option<Word> = store.get_leaf(&key1).unwrap();
+    assert_eq!(leaf.unwrap().0, "a");
+    let leaf: Option<Word> = store.get_leaf(&key2).unwrap();
+    assert!(leaf.is_none());
+}
+
+#[test]
+fn test_prune_versions() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+
+    for i in 0..5u8 {
+        let store = JournalStore::new(DefaultStoreMultiTree::new(b"tree1", &db), true);
+        let mut smt = JournalStoreSMT::new_with_store(store).unwrap();
+        smt.update([i; 32].into(), Word(i.to_string())).unwrap();
+        record_version(&db, b"tree1", smt.root(), None, smt.store().changes(), 2).unwrap();
+    }
+
+    let snapshot = db.snapshot();
+    let versions: Vec<u64> = read_versions(&snapshot, b"tree1")
+        .unwrap()
+        .into_iter()
+        .map(|r| r.version)
+        .collect();
+    assert_eq!(versions, vec![4, 5]);
+
+    // the oldest retained version is still served
+    let store = VersionedStore::new(
+        &snapshot,
+        b"tree1",
+        Some(4),
+        DefaultStoreMultiTree::<_, ()>::new(b"tree1", &snapshot),
+    );
+    let leaf: Option<Word> = store.get_leaf(&[4u8; 32].into()).unwrap();
+    assert!(leaf.is_none());
+    let leaf: Option<Word> = store.get_leaf(&[3u8; 32].into()).unwrap();
+    assert_eq!(leaf.unwrap().0, "3");
+}
+
+#[test]
+fn test_rollback() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+
+    let mut roots = Vec::new();
+    for i in 0..4u8 {
+        let store = JournalStore::new(DefaultStoreMultiTree::new(b"tree1", &db), true);
+        let mut smt = JournalStoreSMT::new_with_store(store).unwrap();
+        // overwrite a leaf of every version and add a new one
+        smt.update_all(vec![
+            ([0u8; 32].into(), Word(i.to_string())),
+            ([i + 1; 32].into(), Word(i.to_string())),
+        ])
+        .unwrap();
+        record_version(&db, b"tree1", smt.root(), None, smt.store().changes(), 10).unwrap();
+        roots.push(*smt.root());
+    }
+
+    let leaves: Vec<(H256, Word)> = rollback_leaves(&db, b"tree1", 2).unwrap();
+    // the overwritten leaf and the two added leaves
+    assert_eq!(leaves.len(), 3);
+    let mut smt =
+        DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::new(b"tree1", &db)).unwrap();
+    smt.update_all(leaves).unwrap();
+    assert_eq!(smt.root(), &roots[1]);
+    assert_eq!(truncate_versions(&db, b"tree1", 2).unwrap(), 2);
+
+    let snapshot = db.snapshot();
+    let versions = read_versions(&snapshot, b"tree1").unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[1].root, roots[1]);
+    // the undo records of the removed versions are gone, the current state is read at the latest version
+    let store = VersionedStore::new(
+        &snapshot,
+        b"tree1",
+        Some(2),
+        DefaultStoreMultiTree::<_, ()>::new(b"tree1", &snapshot),
+    );
+    let leaf: Option<Word> = store.get_leaf(&[0u8; 32].into()).unwrap();
+    assert_eq!(leaf.unwrap().0, "1");
+    assert!(rollback_leaves::<_, Word>(&snapshot, b"tree1", 2)
+        .unwrap()
+        .is_empty());
+
+    // the numbers of the truncated versions are not given out again
+    let store = JournalStore::new(DefaultStoreMultiTree::new(b"tree1", &db), true);
+    let mut smt = JournalStoreSMT::new_with_store(store).unwrap();
+    smt.update([9u8; 32].into(), Word("9".to_string())).unwrap();
+    let version =
+        record_version(&db, b"tree1", smt.root(), None, smt.store().changes(), 10).unwrap();
+    assert_eq!(version, 5);
+    let store = VersionedStore::new(
+        &db,
+        b"tree1",
+        Some(2),
+        DefaultStoreMultiTree::<_, ()>::new(b"tree1", &db),
+    );
+    let leaf: Option<Word> = store.get_leaf(&[9u8; 32].into()).unwrap();
+    assert!(leaf.is_none());
+}
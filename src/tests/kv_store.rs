@@ -0,0 +1,91 @@
+use rocksdb::{prelude::Open, DB};
+
+use crate::kv_store::{
+    forget_abandoned_tree, get_abandoned_trees, get_smt_tree_name, get_smt_tree_version,
+    swap_smt_trees, tree_name_to_string, upgrade_smt_tree_version, versioned_tree_name,
+};
+
+#[test]
+fn test_tree_versions() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+
+    // the trees written before they were versioned keep their names
+    assert_eq!(get_smt_tree_version(&db, "tree1").unwrap(), 0);
+    assert_eq!(get_smt_tree_name(&db, "tree1").unwrap(), b"tree1".to_vec());
+    assert!(get_abandoned_trees(&db).is_empty());
+
+    assert_eq!(upgrade_smt_tree_version(&db, "tree1").unwrap(), 1);
+    assert_eq!(upgrade_smt_tree_version(&db, "tree1").unwrap(), 2);
+    let tree_name = get_smt_tree_name(&db, "tree1").unwrap();
+    assert_eq!(tree_name, versioned_tree_name("tree1", 2));
+    assert_eq!(tree_name_to_string(&tree_name), "tree1#2");
+    // the versions of the other trees are not changed
+    assert_eq!(
+        get_smt_tree_name(&db, "tree10").unwrap(),
+        b"tree10".to_vec()
+    );
+
+    let abandoned = get_abandoned_trees(&db);
+    assert_eq!(
+        abandoned,
+        vec![b"tree1".to_vec(), versioned_tree_name("tree1", 1)]
+    );
+    forget_abandoned_tree(&db, &abandoned[0]).unwrap();
+    assert_eq!(
+        get_abandoned_trees(&db),
+        vec![versioned_tree_name("tree1", 1)]
+    );
+}
+
+#[test]
+fn test_versioned_tree_names_are_distinct() {
+    // a versioned name is never the name of another tree, the separator is not valid utf-8
+    let versioned = versioned_tree_name("tree1", 1);
+    assert!(String::from_utf8(versioned.clone()).is_err());
+    assert_ne!(versioned, versioned_tree_name("tree1", 256));
+    assert_eq!(tree_name_to_string(b"tree1"), "tree1");
+}
+
+#[test]
+fn test_swap_trees() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = DB::open_default(tmp_dir.path()).unwrap();
+
+    upgrade_smt_tree_version(&db, "tree1-tmp").unwrap();
+    assert_eq!(
+        swap_smt_trees(&db, "tree1", "tree1-tmp").unwrap(),
+        (versioned_tree_name("tree1-tmp", 1), b"tree1".to_vec())
+    );
+    assert_eq!(
+        get_smt_tree_name(&db, "tree1").unwrap(),
+        versioned_tree_name("tree1-tmp", 1)
+    );
+    assert_eq!(
+        get_smt_tree_name(&db, "tree1-tmp").unwrap(),
+        b"tree1".to_vec()
+    );
+    assert!(swap_smt_trees(&db, "tree1", "tree1").is_err());
+
+    // a reset abandons the records the tree is stored under, the new versioned name is not used by any tree
+    assert_eq!(upgrade_smt_tree_version(&db, "tree1-tmp").unwrap(), 2);
+    assert_eq!(
+        get_smt_tree_name(&db, "tree1-tmp").unwrap(),
+        versioned_tree_name("tree1-tmp", 2)
+    );
+    assert_eq!(
+        get_abandoned_trees(&db),
+        vec![b"tree1".to_vec(), versioned_tree_name("tree1-tmp", 0)]
+    );
+
+    // the trees can be swapped again, e.g. to roll back a swap
+    swap_smt_trees(&db, "tree1", "tree1-tmp").unwrap();
+    assert_eq!(
+        get_smt_tree_name(&db, "tree1").unwrap(),
+        versioned_tree_name("tree1-tmp", 2)
+    );
+    assert_eq!(
+        get_smt_tree_name(&db, "tree1-tmp").unwrap(),
+        versioned_tree_name("tree1-tmp", 1)
+    );
+}
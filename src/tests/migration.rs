@@ -0,0 +1,275 @@
+use rocksdb::{
+    prelude::{GetColumnFamilys, Iterate, IterateCF, Open, OpenCF, Put, PutCF},
+    IteratorMode, OptimisticTransactionDB, Options, DB,
+};
+use sparse_merkle_tree::{blake2b::Blake2bHasher, SparseMerkleTree, H256};
+
+use crate::cf_store::{repair_stray_leaves, ColumnFamilyStoreMultiTree};
+use crate::default_store::DefaultStoreMultiTree;
+use crate::kv_store::{
+    get_abandoned_trees, get_smt_tree_name, get_smt_tree_version, upgrade_smt_tree_version,
+};
+use crate::migration::{
+    migrate_legacy_cf_database, migrate_legacy_database, migrate_legacy_tree_versions,
+    LEGACY_VERSION_DB_DIR,
+};
+use crate::schema::{check_metadata, read_metadata, Metadata};
+use crate::serde::BranchNodeEncoding;
+use crate::serde::TREE_KEY_TAG;
+use crate::tree_store::{StoreLayout, BRANCH_CF, LEAF_CF};
+
+use super::{new_blake2b, Word};
+
+type DefaultStoreMultiSMT<'a, T, W> =
+    SparseMerkleTree<Blake2bHasher, Word, DefaultStoreMultiTree<'a, T, W>>;
+
+#[test]
+fn test_migrate_legacy_database() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = OptimisticTransactionDB::open_default(tmp_dir.path()).unwrap();
+
+    let names: Vec<&[u8]> = vec![&b"tree1"[..], &b"tree10"[..], &b"parent.bit"[..]];
+    let mut roots = Vec::new();
+    {
+        let tx = db.transaction_default();
+        for (i, name) in names.iter().enumerate() {
+            let mut smt = DefaultStoreMultiSMT::new_with_store(
+                DefaultStoreMultiTree::new_with_raw_prefix(name, &tx),
+            )
+            .unwrap();
+            smt.update_all(kvs[i..].to_vec()).unwrap();
+            roots.push(smt.root().clone());
+        }
+        tx.commit().unwrap();
+    }
+
+    // a legacy database can not be served
+    assert!(check_metadata(&db, BranchNodeEncoding::default(), StoreLayout::default()).is_err());
+
+    let mut migrated = migrate_legacy_database::<Blake2bHasher, Word>(&db, true).unwrap();
+    migrated.sort();
+    let mut expected: Vec<(Vec<u8>, H256)> = names
+        .iter()
+        .map(|name| name.to_vec())
+        .zip(roots.into_iter())
+        .collect();
+    expected.sort();
+    assert_eq!(expected, migrated);
+
+    assert_eq!(read_metadata(&db).unwrap(), Some(Metadata::current()));
+    assert!(check_metadata(&db, BranchNodeEncoding::default(), StoreLayout::default()).is_ok());
+
+    // only the records of the current layout are left
+    let snapshot = db.snapshot();
+    for (k, _) in snapshot.iterator(IteratorMode::Start) {
+        assert!(k[0] == TREE_KEY_TAG || k[..] == b"\x00metadata"[..]);
+    }
+    for (name, root) in expected {
+        let smt = DefaultStoreMultiSMT::new_with_store(DefaultStoreMultiTree::<_, ()>::new(
+            &name, &snapshot,
+        ))
+        .unwrap();
+        assert_eq!(&root, smt.root());
+    }
+}
+
+type ColumnFamilyStoreMultiSMT<'a, T, W> =
+    SparseMerkleTree<Blake2bHasher, Word, ColumnFamilyStoreMultiTree<'a, T, W>>;
+
+#[test]
+fn test_migrate_legacy_cf_database() {
+    let kvs = "The quick brown fox jumps over the lazy dog"
+        .split_whitespace()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut buf = [0u8; 32];
+            let mut hasher = new_blake2b();
+            hasher.update(&(i as u32).to_le_bytes());
+            hasher.finalize(&mut buf);
+            (buf.into(), Word(word.to_string()))
+        })
+        .collect::<Vec<(H256, Word)>>();
+
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let mut options = Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    let db = OptimisticTransactionDB::open_cf(&options, tmp_dir.path(), vec![BRANCH_CF, LEAF_CF])
+        .unwrap();
+    let branch_col = db.cf_handle(BRANCH_CF).unwrap();
+    let leaf_col = db.cf_handle(LEAF_CF).unwrap();
+
+    let names: Vec<&[u8]> = vec![&b"tree1"[..], &b"tree10"[..], &b"parent.bit"[..]];
+    let mut roots = Vec::new();
+    {
+        let tx = db.transaction_default();
+        for (i, name) in names.iter().enumerate() {
+            // the legacy store wrote both the branches and the leaves to the branch column family
+            let mut smt = ColumnFamilyStoreMultiSMT::new_with_store(
+                ColumnFamilyStoreMultiTree::new_with_raw_prefix(name, &tx, branch_col, branch_col),
+            )
+            .unwrap();
+            smt.update_all(kvs[i..].to_vec()).unwrap();
+            roots.push(smt.root().clone());
+        }
+        // and it removed the leaves from the leaf column family, leaving the stale records behind
+        tx.put_cf(branch_col, &[&b"tree1"[..], &[9u8; 32]].concat(), b"stale")
+            .unwrap();
+        tx.commit().unwrap();
+    }
+    let leaves_num: usize = (0..names.len()).map(|i| kvs.len() - i).sum();
+    assert_eq!(
+        repair_stray_leaves::<Blake2bHasher, Word>(&db, branch_col, leaf_col).unwrap(),
+        leaves_num
+    );
+    assert_eq!(
+        db.iterator_cf(leaf_col, IteratorMode::Start)
+            .unwrap()
+            .count(),
+        leaves_num
+    );
+
+    // the default column family is empty, the legacy trees are found in the column families
+    assert!(check_metadata(
+        &db,
+        BranchNodeEncoding::default(),
+        StoreLayout::ColumnFamily
+    )
+    .is_err());
+    assert_eq!(read_metadata(&db).unwrap(), None);
+
+    let mut migrated =
+        migrate_legacy_cf_database::<Blake2bHasher, Word>(&db, branch_col, leaf_col, true).unwrap();
+    migrated.sort();
+    let mut expected: Vec<(Vec<u8>, H256)> = names
+        .iter()
+        .map(|name| name.to_vec())
+        .zip(roots.into_iter())
+        .collect();
+    expected.sort();
+    assert_eq!(expected, migrated);
+
+    let metadata = read_metadata(&db).unwrap().unwrap();
+    assert_eq!(metadata.store_layout, StoreLayout::ColumnFamily);
+    assert!(check_metadata(
+        &db,
+        BranchNodeEncoding::default(),
+        StoreLayout::ColumnFamily
+    )
+    .is_ok());
+
+    // only the records of the current layout are left
+    let snapshot = db.snapshot();
+    for col in [branch_col, leaf_col] {
+        for (k, _) in snapshot.iterator_cf(col, IteratorMode::Start).unwrap() {
+            assert_eq!(k[0], TREE_KEY_TAG);
+        }
+    }
+    for (name, root) in expected {
+        let smt = ColumnFamilyStoreMultiSMT::new_with_store(
+            ColumnFamilyStoreMultiTree::<_, ()>::new(&name, &snapshot, branch_col, leaf_col),
+        )
+        .unwrap();
+        assert_eq!(&root, smt.root());
+    }
+}
+
+#[test]
+fn test_metadata_of_new_database() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = OptimisticTransactionDB::open_default(tmp_dir.path()).unwrap();
+    assert_eq!(read_metadata(&db).unwrap(), None);
+    assert_eq!(
+        check_metadata(&db, BranchNodeEncoding::default(), StoreLayout::default()).unwrap(),
+        Metadata::current()
+    );
+    assert_eq!(read_metadata(&db).unwrap(), Some(Metadata::current()));
+}
+
+#[test]
+fn test_incompatible_metadata() {
+    let mut metadata = Metadata::current();
+    assert!(metadata.check_compatible().is_ok());
+    metadata.trie = !metadata.trie;
+    assert!(metadata.check_compatible().is_err());
+
+    let mut metadata = Metadata::current();
+    metadata.schema_version += 1;
+    assert!(metadata.check_compatible().is_err());
+
+    let mut metadata = Metadata::current();
+    metadata.hasher = "blake2b-256/sparse-merkle-tree".to_string();
+    assert!(metadata.check_compatible().is_err());
+}
+
+#[test]
+fn test_branch_node_encoding_of_new_database() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = OptimisticTransactionDB::open_default(tmp_dir.path()).unwrap();
+    let metadata =
+        check_metadata(&db, BranchNodeEncoding::Compact, StoreLayout::default()).unwrap();
+    assert_eq!(metadata.branch_node_encoding, BranchNodeEncoding::Compact);
+    // the encoding of an existing database is kept
+    let metadata = check_metadata(&db, BranchNodeEncoding::Full, StoreLayout::default()).unwrap();
+    assert_eq!(metadata.branch_node_encoding, BranchNodeEncoding::Compact);
+    // an existing database can only be opened with the layout it's created with
+    assert!(check_metadata(&db, BranchNodeEncoding::Full, StoreLayout::ColumnFamily).is_err());
+}
+
+#[test]
+fn test_migrate_legacy_tree_versions() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = OptimisticTransactionDB::open_default(tmp_dir.path()).unwrap();
+    let version_db_path = tmp_dir.path().join(LEGACY_VERSION_DB_DIR);
+
+    // nothing to migrate without a legacy version database
+    assert!(migrate_legacy_tree_versions(&db, &version_db_path)
+        .unwrap()
+        .is_empty());
+
+    {
+        let version_db = DB::open_default(&version_db_path).unwrap();
+        version_db.put(b"tree1", b"0").unwrap();
+        version_db.put(b"tree2", b"3").unwrap();
+    }
+    let mut migrated = migrate_legacy_tree_versions(&db, &version_db_path).unwrap();
+    migrated.sort();
+    assert_eq!(
+        migrated,
+        vec![("tree1".to_string(), 0), ("tree2".to_string(), 3)]
+    );
+    // the trees keep the records written under their legacy names
+    assert_eq!(
+        get_smt_tree_name(&db, "tree1").unwrap(),
+        b"tree1_0".to_vec()
+    );
+    assert_eq!(
+        get_smt_tree_name(&db, "tree2").unwrap(),
+        b"tree2_3".to_vec()
+    );
+    assert_eq!(get_smt_tree_version(&db, "tree2").unwrap(), 3);
+    assert_eq!(get_smt_tree_name(&db, "tree3").unwrap(), b"tree3".to_vec());
+
+    // migrating again changes nothing
+    assert!(migrate_legacy_tree_versions(&db, &version_db_path)
+        .unwrap()
+        .is_empty());
+
+    // a reset abandons the legacy name
+    assert_eq!(upgrade_smt_tree_version(&db, "tree2").unwrap(), 4);
+    assert_eq!(get_abandoned_trees(&db), vec![b"tree2_3".to_vec()]);
+    // the tree is versioned since, so the legacy version can not be migrated again
+    assert!(migrate_legacy_tree_versions(&db, &version_db_path).is_err());
+}
@@ -6,6 +6,11 @@ use sparse_merkle_tree::{
 
 mod cf_store;
 mod default_store;
+mod gc;
+mod history;
+mod kv_store;
+mod migration;
+mod rpc_server;
 
 #[derive(Default, Clone)]
 pub struct Word(String);
@@ -0,0 +1,663 @@
+use jsonrpsee::{core::Error, types::error::CallError};
+use rocksdb::{
+    prelude::{Open, OpenCF, Put},
+    OptimisticTransactionDB, OptimisticTransactionOptions, Options, WriteOptions,
+};
+use sparse_merkle_tree::{BranchKey, H256};
+
+use crate::rpc_server::{is_write_conflict, RpcServer, RpcServerImpl, ROOT_MISMATCH_ERROR_CODE};
+use crate::serde::{branch_key_to_vec, tree_name_to_prefix};
+use crate::structures::{
+    Opt, Pair, ProofMode, SmtKey, SmtProof, SmtRoot, SmtValue, SmtVersionQuery,
+};
+use crate::tree_store::{
+    ColumnFamilyBackend, DefaultBackend, StoreBackend, TreeColumnFamilyBackend, BRANCH_CF, LEAF_CF,
+};
+use crate::utils::slice_to_hex_string;
+
+pub fn new_server(path: &std::path::Path) -> RpcServerImpl {
+    RpcServerImpl::new(OptimisticTransactionDB::open_default(path).unwrap())
+}
+
+pub fn new_backend_server<B: StoreBackend>(path: &std::path::Path) -> RpcServerImpl<B> {
+    let mut options = Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    let db = OptimisticTransactionDB::open_cf(&options, path, vec![BRANCH_CF, LEAF_CF]).unwrap();
+    RpcServerImpl::new(db).with_backend::<B>()
+}
+
+pub fn pair(key: u8, value: u8) -> Pair {
+    Pair {
+        key: SmtKey([key; 32]),
+        value: SmtValue([value; 32]),
+    }
+}
+
+pub fn error_code(err: &Error) -> Option<i32> {
+    match err {
+        Error::Call(CallError::Custom(e)) => Some(e.code()),
+        _ => None,
+    }
+}
+
+// a proof returned hex encoded, as it's passed to `verify_smt_proof`
+pub fn proof_from_hex(hex: &str) -> SmtProof {
+    serde_json::from_value(serde_json::Value::String(hex.to_string())).unwrap()
+}
+
+fn with_root() -> Opt {
+    Opt {
+        get_root: true,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_get_smt_values() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_server(tmp_dir.path());
+
+    server
+        .update_rocksdb_smt(with_root(), "tree1", vec![pair(1, 1), pair(2, 2)], None)
+        .await
+        .unwrap();
+    // a key updated to zero is removed
+    server
+        .update_rocksdb_smt(with_root(), "tree1", vec![pair(2, 0)], None)
+        .await
+        .unwrap();
+
+    let keys = vec![SmtKey([1u8; 32]), SmtKey([2u8; 32]), SmtKey([3u8; 32])];
+    let leaves = server.get_smt_values("tree1", keys, None).await.unwrap();
+    // the leaves are returned in the order of the keys
+    assert_eq!(leaves.len(), 3);
+    assert_eq!(leaves[0].key.0, [1u8; 32]);
+    assert_eq!(leaves[0].value.as_ref().unwrap().0, [1u8; 32]);
+    assert_eq!(leaves[1].key.0, [2u8; 32]);
+    assert!(leaves[1].value.is_none());
+    assert_eq!(leaves[2].key.0, [3u8; 32]);
+    assert!(leaves[2].value.is_none());
+
+    // every key of an empty tree is absent
+    let leaves = server
+        .get_smt_values("tree2", vec![SmtKey([1u8; 32])], None)
+        .await
+        .unwrap();
+    assert!(leaves[0].value.is_none());
+}
+
+#[tokio::test]
+async fn test_get_smt_proof() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_server(tmp_dir.path());
+
+    server
+        .update_rocksdb_smt(
+            with_root(),
+            "tree1",
+            vec![pair(1, 1), pair(2, 2), pair(3, 3)],
+            None,
+        )
+        .await
+        .unwrap();
+    let r = server
+        .get_smt_proof("tree1", vec![SmtKey([1u8; 32]), SmtKey([2u8; 32])], None)
+        .await
+        .unwrap();
+    assert_eq!(
+        r.root.0,
+        server.get_smt_root("tree1", None).await.unwrap().0
+    );
+
+    // each key has a proof of its own
+    assert_eq!(r.proofs.len(), 2);
+    for (key, value) in [(1u8, 1u8), (2, 2)] {
+        let proof = &r.proofs[&slice_to_hex_string(&[key; 32])];
+        let verified = server
+            .verify_smt_proof(
+                r.root.clone(),
+                proof_from_hex(proof),
+                vec![pair(key, value)],
+            )
+            .await
+            .unwrap();
+        assert!(verified);
+        // the proof does not verify another value
+        let verified = server
+            .verify_smt_proof(r.root.clone(), proof_from_hex(proof), vec![pair(key, 9)])
+            .await
+            .unwrap();
+        assert!(!verified);
+    }
+
+    // the multi proof covers all the keys at once
+    let verified = server
+        .verify_smt_proof(
+            r.root.clone(),
+            proof_from_hex(&r.multi_proof),
+            vec![pair(1, 1), pair(2, 2)],
+        )
+        .await
+        .unwrap();
+    assert!(verified);
+}
+
+#[tokio::test]
+async fn test_proof_mode() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_server(tmp_dir.path());
+
+    let opt = |proof_mode| Opt {
+        get_root: true,
+        get_proof: true,
+        proof_mode,
+        ..Default::default()
+    };
+    let kvs = vec![pair(1, 1), pair(2, 2)];
+
+    // a single proof of all the keys and no proof per key
+    let r = server
+        .update_rocksdb_smt(opt(ProofMode::Combined), "tree1", kvs.clone(), None)
+        .await
+        .unwrap();
+    assert!(r.proofs.is_empty());
+    let verified = server
+        .verify_smt_proof(
+            r.root.clone(),
+            proof_from_hex(&r.multi_proof.unwrap()),
+            kvs.clone(),
+        )
+        .await
+        .unwrap();
+    assert!(verified);
+
+    // both kinds of proofs
+    let kvs = vec![pair(1, 3), pair(4, 4)];
+    let r = server
+        .update_rocksdb_smt(opt(ProofMode::Both), "tree1", kvs.clone(), None)
+        .await
+        .unwrap();
+    assert_eq!(r.proofs.len(), 2);
+    let verified = server
+        .verify_smt_proof(
+            r.root.clone(),
+            proof_from_hex(&r.multi_proof.unwrap()),
+            kvs.clone(),
+        )
+        .await
+        .unwrap();
+    assert!(verified);
+    for kv in kvs.iter() {
+        let verified = server
+            .verify_smt_proof(
+                r.root.clone(),
+                proof_from_hex(&r.proofs[&slice_to_hex_string(&kv.key.0)]),
+                vec![kv.clone()],
+            )
+            .await
+            .unwrap();
+        assert!(verified);
+    }
+
+    // the proofs per key only by default
+    let r = server
+        .update_rocksdb_smt(opt(ProofMode::default()), "tree1", vec![pair(5, 5)], None)
+        .await
+        .unwrap();
+    assert_eq!(r.proofs.len(), 1);
+    assert!(r.multi_proof.is_none());
+
+    // the tree built in memory is proven the same way
+    let r = server
+        .build_memory_smt(opt(ProofMode::Combined), "tree2", kvs.clone())
+        .await
+        .unwrap();
+    assert!(r.proofs.is_empty());
+    let verified = server
+        .verify_smt_proof(r.root.clone(), proof_from_hex(&r.multi_proof.unwrap()), kvs)
+        .await
+        .unwrap();
+    assert!(verified);
+}
+
+#[tokio::test]
+async fn test_non_membership_proof() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_server(tmp_dir.path());
+
+    server
+        .update_rocksdb_smt(with_root(), "tree1", vec![pair(1, 1)], None)
+        .await
+        .unwrap();
+
+    // the proof of an unset key verifies with the zero value
+    let r = server
+        .get_smt_non_membership_proof("tree1", SmtKey([2u8; 32]), None)
+        .await
+        .unwrap();
+    assert!(r.is_empty);
+    assert_eq!(
+        r.root.0,
+        server.get_smt_root("tree1", None).await.unwrap().0
+    );
+    let verified = server
+        .verify_smt_proof(r.root.clone(), proof_from_hex(&r.proof), vec![pair(2, 0)])
+        .await
+        .unwrap();
+    assert!(verified);
+
+    // a set key is reported, its proof does not verify with the zero value
+    let r = server
+        .get_smt_non_membership_proof("tree1", SmtKey([1u8; 32]), None)
+        .await
+        .unwrap();
+    assert!(!r.is_empty);
+    let verified = server
+        .verify_smt_proof(r.root.clone(), proof_from_hex(&r.proof), vec![pair(1, 0)])
+        .await
+        .unwrap();
+    assert!(!verified);
+}
+
+#[tokio::test]
+async fn test_verify_malformed_proof() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_server(tmp_dir.path());
+
+    let r = server
+        .update_rocksdb_smt(with_root(), "tree1", vec![pair(1, 1)], None)
+        .await
+        .unwrap();
+
+    // a proof which is not a compiled proof is an error, not a failed verification
+    let err = server
+        .verify_smt_proof(r.root.clone(), SmtProof(vec![0xff]), vec![pair(1, 1)])
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        Error::Custom(msg) if msg.starts_with("malformed proof") && msg.contains("unknown opcode 0xff")
+    ));
+
+    // and so is a truncated proof
+    let proof = server
+        .get_smt_proof("tree1", vec![SmtKey([1u8; 32])], None)
+        .await
+        .unwrap()
+        .multi_proof;
+    let mut proof = proof_from_hex(&proof);
+    proof.0.truncate(proof.0.len() - 1);
+    let err = server
+        .verify_smt_proof(r.root.clone(), proof, vec![pair(1, 1)])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Custom(msg) if msg.starts_with("malformed proof")));
+}
+
+#[tokio::test]
+async fn test_atomic_update() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let mut key = [0xffu8; 32];
+    key[0] = 0xfe;
+    let pairs = vec![
+        pair(0xff, 1),
+        Pair {
+            key: SmtKey(key),
+            value: SmtValue([1u8; 32]),
+        },
+    ];
+    let root = new_server(tmp_dir.path())
+        .update_rocksdb_smt(with_root(), "tree1", pairs, None)
+        .await
+        .unwrap()
+        .root
+        .0;
+
+    // corrupt a branch node on the path of the key 0xff.., the keys on the other side of the root never read it
+    {
+        let db = OptimisticTransactionDB::open_default(tmp_dir.path()).unwrap();
+        let branch_key = BranchKey::new(200, H256::from([0xffu8; 32]).parent_path(200));
+        db.put(
+            [
+                tree_name_to_prefix(b"tree1"),
+                branch_key_to_vec(&branch_key),
+            ]
+            .concat(),
+            [9u8; 3],
+        )
+        .unwrap();
+    }
+    let server = new_server(tmp_dir.path());
+
+    // the first chunk is fine, the second one fails
+    let mut kvs: Vec<Pair> = (0..5000u16)
+        .map(|i| {
+            let mut key = [0u8; 32];
+            key[..2].copy_from_slice(&i.to_be_bytes());
+            Pair {
+                key: SmtKey(key),
+                value: SmtValue([1u8; 32]),
+            }
+        })
+        .collect();
+    kvs.push(pair(0xff, 2));
+    let first_key = || SmtKey([0u8; 32]);
+
+    assert!(server
+        .update_rocksdb_smt(with_root(), "tree1", kvs.clone(), None)
+        .await
+        .is_err());
+    // nothing is committed
+    assert_eq!(server.get_smt_root("tree1", None).await.unwrap().0, root);
+    assert!(server
+        .get_smt_value("tree1", first_key(), None)
+        .await
+        .unwrap()
+        .is_none());
+
+    // the chunks before the failure are committed if it's not atomic
+    let opt = Opt {
+        get_root: true,
+        atomic: false,
+        ..Default::default()
+    };
+    assert!(server
+        .update_rocksdb_smt(opt, "tree1", kvs, None)
+        .await
+        .is_err());
+    assert_ne!(server.get_smt_root("tree1", None).await.unwrap().0, root);
+    assert!(server
+        .get_smt_value("tree1", first_key(), None)
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn test_expected_root() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_server(tmp_dir.path());
+
+    let r = server
+        .update_rocksdb_smt(with_root(), "tree1", vec![pair(1, 1)], None)
+        .await
+        .unwrap();
+    let root = r.root.0;
+
+    // the tree is not empty any more, an update expecting the empty root is rejected
+    let err = server
+        .update_rocksdb_smt(
+            with_root(),
+            "tree1",
+            vec![pair(2, 2)],
+            Some(SmtRoot::default()),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(error_code(&err), Some(ROOT_MISMATCH_ERROR_CODE));
+    assert_eq!(server.get_smt_root("tree1", None).await.unwrap().0, root);
+
+    let r = server
+        .update_rocksdb_smt(with_root(), "tree1", vec![pair(2, 2)], Some(SmtRoot(root)))
+        .await
+        .unwrap();
+    assert_ne!(r.root.0, root);
+    assert_eq!(
+        server.get_smt_root("tree1", None).await.unwrap().0,
+        r.root.0
+    );
+}
+
+#[test]
+fn test_write_conflict() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let db = OptimisticTransactionDB::open_default(tmp_dir.path()).unwrap();
+
+    let mut txn_options = OptimisticTransactionOptions::new();
+    txn_options.set_snapshot(true);
+    let tx = db.transaction(&WriteOptions::default(), &txn_options);
+    tx.put(b"key", b"1").unwrap();
+    // another commit writes the key after the snapshot of the transaction
+    db.put(b"key", b"2").unwrap();
+    let err = tx.commit().unwrap_err();
+    assert!(is_write_conflict(&err));
+}
+
+#[tokio::test]
+async fn test_dry_run() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_server(tmp_dir.path());
+
+    let opt = Opt {
+        get_root: true,
+        dry_run: true,
+        ..Default::default()
+    };
+    let r = server
+        .update_rocksdb_smt(opt.clone(), "tree1", vec![pair(1, 1)], None)
+        .await
+        .unwrap();
+    assert_ne!(r.root.0, [0u8; 32]);
+    let r = server
+        .update_rocksdb_smt_sequence(opt, "tree1", vec![pair(1, 1), pair(2, 2)], None)
+        .await
+        .unwrap();
+    assert_eq!(r.steps.len(), 2);
+
+    // nothing is persisted
+    assert_eq!(
+        server.get_smt_root("tree1", None).await.unwrap().0,
+        [0u8; 32]
+    );
+    assert!(server
+        .get_smt_value("tree1", SmtKey([1u8; 32]), None)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_steps_and_get_previous() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_server(tmp_dir.path());
+
+    let opt = Opt {
+        get_root: true,
+        get_proof: true,
+        ..Default::default()
+    };
+    // a repeated key has a step for each of its updates
+    let r = server
+        .update_rocksdb_smt_sequence(opt, "tree1", vec![pair(1, 1), pair(2, 2), pair(1, 3)], None)
+        .await
+        .unwrap();
+    assert_eq!(r.steps.len(), 3);
+    for (i, step) in r.steps.iter().enumerate() {
+        assert_eq!(step.index, i);
+        if i > 0 {
+            assert_eq!(step.root_before.0, r.steps[i - 1].root_after.0);
+        }
+        let verified = server
+            .verify_smt_proof(
+                step.root_after.clone(),
+                step.proof.clone(),
+                vec![Pair {
+                    key: step.key.clone(),
+                    value: step.value.clone(),
+                }],
+            )
+            .await
+            .unwrap();
+        assert!(verified);
+    }
+    let root = r.steps[2].root_after.0;
+
+    let opt = Opt {
+        get_root: true,
+        get_previous: true,
+        ..Default::default()
+    };
+    let r = server
+        .update_rocksdb_smt(opt, "tree1", vec![pair(1, 4), pair(5, 5)], None)
+        .await
+        .unwrap();
+    assert_eq!(r.previous_root.unwrap().0, root);
+    let previous_values = r.previous_values.unwrap();
+    assert_eq!(previous_values[0].value.0, [3u8; 32]);
+    assert_eq!(previous_values[1].value.0, [0u8; 32]);
+    assert_eq!(
+        server.get_smt_root("tree1", None).await.unwrap().0,
+        r.root.0
+    );
+}
+
+// purge a tree of the backend `B`, returns the number of removed keys
+async fn purge_smt<B: StoreBackend>() -> usize {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_backend_server::<B>(tmp_dir.path());
+
+    server
+        .update_rocksdb_smt(with_root(), "tree1", vec![pair(1, 1), pair(2, 2)], None)
+        .await
+        .unwrap();
+    let root = server
+        .update_rocksdb_smt(with_root(), "tree2", vec![pair(1, 1)], None)
+        .await
+        .unwrap()
+        .root
+        .0;
+
+    let removed = server.purge_smt("tree1").await.unwrap();
+    assert!(removed > 0);
+    assert_eq!(
+        server.get_smt_root("tree1", None).await.unwrap().0,
+        [0u8; 32]
+    );
+    assert!(server
+        .get_smt_value("tree1", SmtKey([1u8; 32]), None)
+        .await
+        .unwrap()
+        .is_none());
+    // the other trees are untouched
+    assert_eq!(server.get_smt_root("tree2", None).await.unwrap().0, root);
+    // nothing is left to remove
+    assert_eq!(server.purge_smt("tree1").await.unwrap(), 0);
+    removed
+}
+
+#[tokio::test]
+async fn test_purge_smt() {
+    let removed = purge_smt::<DefaultBackend>().await;
+    // the keys swept from the shared column families are counted exactly
+    assert_eq!(purge_smt::<ColumnFamilyBackend>().await, removed);
+    purge_smt::<TreeColumnFamilyBackend>().await;
+}
+
+// copy a tree of the backend `B`
+async fn copy_smt<B: StoreBackend>() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_backend_server::<B>(tmp_dir.path());
+
+    let root = server
+        .update_rocksdb_smt(with_root(), "tree1", vec![pair(1, 1), pair(2, 2)], None)
+        .await
+        .unwrap()
+        .root
+        .0;
+    let r = server.copy_smt("tree1", "tree2").await.unwrap();
+    // the root of the copy is checked against the root of the source
+    assert_eq!(r.root.0, root);
+    assert!(r.copied_keys > 0);
+    assert_eq!(server.get_smt_root("tree2", None).await.unwrap().0, root);
+
+    // the copy is updated independently of the source
+    server
+        .update_rocksdb_smt(with_root(), "tree2", vec![pair(1, 3)], None)
+        .await
+        .unwrap();
+    assert_eq!(server.get_smt_root("tree1", None).await.unwrap().0, root);
+    assert_eq!(
+        server
+            .get_smt_value("tree2", SmtKey([1u8; 32]), None)
+            .await
+            .unwrap()
+            .unwrap()
+            .0,
+        [3u8; 32]
+    );
+
+    // the destination must be empty
+    let err = server.copy_smt("tree1", "tree2").await.unwrap_err();
+    assert!(matches!(err, Error::Custom(msg) if msg.contains("is not empty")));
+    let err = server.copy_smt("tree1", "tree1").await.unwrap_err();
+    assert!(matches!(err, Error::Custom(msg) if msg.contains("to itself")));
+    assert_eq!(server.get_smt_root("tree1", None).await.unwrap().0, root);
+}
+
+#[tokio::test]
+async fn test_copy_smt() {
+    copy_smt::<DefaultBackend>().await;
+    copy_smt::<ColumnFamilyBackend>().await;
+    copy_smt::<TreeColumnFamilyBackend>().await;
+}
+
+#[tokio::test]
+async fn test_copy_smt_history() {
+    let tmp_dir = tempfile::Builder::new().tempdir().unwrap();
+    let server = new_server(tmp_dir.path()).with_history_window(10);
+
+    let root = server
+        .update_rocksdb_smt(with_root(), "tree1", vec![pair(1, 1), pair(2, 2)], None)
+        .await
+        .unwrap()
+        .root
+        .0;
+    // the destination is emptied again, but its versions are retained
+    server
+        .update_rocksdb_smt(with_root(), "tree2", vec![pair(3, 3)], None)
+        .await
+        .unwrap();
+    server
+        .update_rocksdb_smt(with_root(), "tree2", vec![pair(3, 0)], None)
+        .await
+        .unwrap();
+    assert_eq!(server.get_smt_versions("tree2").await.unwrap().len(), 2);
+
+    server.copy_smt("tree1", "tree2").await.unwrap();
+    // the versions recorded before the copy do not describe the copied records
+    assert!(server.get_smt_versions("tree2").await.unwrap().is_empty());
+    let at_version_1 = SmtVersionQuery {
+        version: Some(1),
+        ..Default::default()
+    };
+    assert!(server
+        .get_smt_root("tree2", Some(at_version_1.clone()))
+        .await
+        .is_err());
+    assert!(server.rollback_smt("tree2", at_version_1).await.is_err());
+    assert_eq!(server.get_smt_root("tree2", None).await.unwrap().0, root);
+    // the history of the source is untouched
+    assert_eq!(server.get_smt_versions("tree1").await.unwrap().len(), 1);
+
+    // the updates after the copy are recorded from the copied records
+    let version = server
+        .update_rocksdb_smt(with_root(), "tree2", vec![pair(1, 3)], None)
+        .await
+        .unwrap()
+        .version
+        .unwrap();
+    let at = SmtVersionQuery {
+        version: Some(version),
+        ..Default::default()
+    };
+    assert_eq!(
+        server
+            .get_smt_value("tree2", SmtKey([2u8; 32]), Some(at))
+            .await
+            .unwrap()
+            .unwrap()
+            .0,
+        [2u8; 32]
+    );
+}
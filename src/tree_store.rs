@@ -0,0 +1,286 @@
+use std::str::FromStr;
+
+use rocksdb::{prelude::*, ColumnFamily, OptimisticTransactionDB};
+use serde::{Deserialize, Serialize};
+use sparse_merkle_tree::{
+    error::Error,
+    traits::{StoreReadOps, StoreWriteOps, Value},
+    BranchKey, BranchNode, H256,
+};
+
+use crate::cf_store::{ColumnFamilyStoreMultiTree, ColumnFamilyStorePerTree};
+use crate::default_store::DefaultStoreMultiTree;
+use crate::serde::{tree_name_to_prefix, BranchNodeEncoding};
+use crate::utils::slice_to_hex_string;
+
+/// The column family of the branch nodes in the column family layout.
+pub const BRANCH_CF: &str = "branch";
+/// The column family of the leaves in the column family layout.
+pub const LEAF_CF: &str = "leaf";
+/// The prefix of the names of the column families of the trees in the tree column family layout.
+pub const TREE_CF_PREFIX: &str = "tree-";
+
+/// The name of the column family of the tree `tree_name` in the tree column family layout, the tree name is hex encoded
+/// since it can be any bytes.
+pub fn tree_column_family_name(tree_name: &[u8]) -> String {
+    format!("{}{}", TREE_CF_PREFIX, slice_to_hex_string(tree_name))
+}
+
+/// The layout of the trees in a database, it's chosen when the database is created and recorded in its metadata.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreLayout {
+    // `DefaultStoreMultiTree`, the branch nodes and the leaves are stored in the default column family
+    Default,
+    // `ColumnFamilyStoreMultiTree`, the branch nodes and the leaves are stored in `BRANCH_CF` and `LEAF_CF`
+    ColumnFamily,
+    // `ColumnFamilyStorePerTree`, each tree is stored in a column family of its own, see `tree_column_family_name`
+    TreeColumnFamily,
+}
+
+impl Default for StoreLayout {
+    fn default() -> Self {
+        StoreLayout::Default
+    }
+}
+
+impl FromStr for StoreLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(StoreLayout::Default),
+            "column_family" => Ok(StoreLayout::ColumnFamily),
+            "tree_column_family" => Ok(StoreLayout::TreeColumnFamily),
+            _ => Err(format!(
+                "unknown store layout `{}`, expected `default`, `column_family` or `tree_column_family`",
+                s
+            )),
+        }
+    }
+}
+
+/// The store backend of the RPC server, it opens the stores of the trees of one `StoreLayout` and knows where their
+/// records are.
+///
+/// The stores of all the backends are opened as `TreeStore`, since a store type borrowing the transaction or the
+/// snapshot it's opened on can not be an associated type of the backend without generic associated types.
+pub trait StoreBackend: Clone + Send + Sync + 'static {
+    /// The layout of the trees served by the backend, it must be the layout recorded in the metadata of the database.
+    const LAYOUT: StoreLayout;
+
+    /// Open the store of the tree `tree_name` on `inner`, which is a transaction or a snapshot of `db`.
+    fn open<'a, T, W>(
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+        inner: &'a T,
+        encoding: BranchNodeEncoding,
+    ) -> Result<TreeStore<'a, T, W>, Error>;
+
+    /// The column families and the key prefixes of all the records of the tree `tree_name`, the range of the branch
+    /// records first. `None` is the default column family and an empty prefix covers the whole column family.
+    fn ranges<'a>(
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+    ) -> Result<Vec<(Option<&'a ColumnFamily>, Vec<u8>)>, Error>;
+}
+
+/// The backend of the default layout, see `DefaultStoreMultiTree`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultBackend;
+
+impl StoreBackend for DefaultBackend {
+    const LAYOUT: StoreLayout = StoreLayout::Default;
+
+    fn open<'a, T, W>(
+        _db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+        inner: &'a T,
+        encoding: BranchNodeEncoding,
+    ) -> Result<TreeStore<'a, T, W>, Error> {
+        Ok(TreeStore::Default(
+            DefaultStoreMultiTree::new(tree_name, inner).with_encoding(encoding),
+        ))
+    }
+
+    fn ranges<'a>(
+        _db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+    ) -> Result<Vec<(Option<&'a ColumnFamily>, Vec<u8>)>, Error> {
+        Ok(vec![(None, tree_name_to_prefix(tree_name))])
+    }
+}
+
+/// The backend of the column family layout, see `ColumnFamilyStoreMultiTree`. The database must be opened with
+/// `BRANCH_CF` and `LEAF_CF`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ColumnFamilyBackend;
+
+impl ColumnFamilyBackend {
+    // the branch and the leaf column families
+    fn column_families(
+        db: &OptimisticTransactionDB,
+    ) -> Result<(&ColumnFamily, &ColumnFamily), Error> {
+        match (db.cf_handle(BRANCH_CF), db.cf_handle(LEAF_CF)) {
+            (Some(branch_col), Some(leaf_col)) => Ok((branch_col, leaf_col)),
+            _ => Err(Error::Store(format!(
+                "the database is not opened with the column families {} and {}",
+                BRANCH_CF, LEAF_CF
+            ))),
+        }
+    }
+}
+
+impl StoreBackend for ColumnFamilyBackend {
+    const LAYOUT: StoreLayout = StoreLayout::ColumnFamily;
+
+    fn open<'a, T, W>(
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+        inner: &'a T,
+        encoding: BranchNodeEncoding,
+    ) -> Result<TreeStore<'a, T, W>, Error> {
+        let (branch_col, leaf_col) = Self::column_families(db)?;
+        Ok(TreeStore::ColumnFamily(
+            ColumnFamilyStoreMultiTree::new(tree_name, inner, branch_col, leaf_col)
+                .with_encoding(encoding),
+        ))
+    }
+
+    fn ranges<'a>(
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+    ) -> Result<Vec<(Option<&'a ColumnFamily>, Vec<u8>)>, Error> {
+        let (branch_col, leaf_col) = Self::column_families(db)?;
+        let prefix = tree_name_to_prefix(tree_name);
+        Ok(vec![
+            (Some(branch_col), prefix.clone()),
+            (Some(leaf_col), prefix),
+        ])
+    }
+}
+
+/// The backend of the tree column family layout, see `ColumnFamilyStorePerTree`. The database must be opened with the
+/// column families of all the existing trees, the column family of a tree is created before the tree is written.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TreeColumnFamilyBackend;
+
+impl StoreBackend for TreeColumnFamilyBackend {
+    const LAYOUT: StoreLayout = StoreLayout::TreeColumnFamily;
+
+    fn open<'a, T, W>(
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+        inner: &'a T,
+        encoding: BranchNodeEncoding,
+    ) -> Result<TreeStore<'a, T, W>, Error> {
+        match db.cf_handle(&tree_column_family_name(tree_name)) {
+            Some(col) => Ok(TreeStore::TreeColumnFamily(
+                ColumnFamilyStorePerTree::new(inner, col).with_encoding(encoding),
+            )),
+            None => Ok(TreeStore::Absent),
+        }
+    }
+
+    fn ranges<'a>(
+        db: &'a OptimisticTransactionDB,
+        tree_name: &[u8],
+    ) -> Result<Vec<(Option<&'a ColumnFamily>, Vec<u8>)>, Error> {
+        match db.cf_handle(&tree_column_family_name(tree_name)) {
+            Some(col) => Ok(vec![(Some(col), Vec::new())]),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// A SMT `Store` implementation over any store layout, so the same code serves the trees of all the layouts.
+pub enum TreeStore<'a, T, W> {
+    Default(DefaultStoreMultiTree<'a, T, W>),
+    ColumnFamily(ColumnFamilyStoreMultiTree<'a, T, W>),
+    TreeColumnFamily(ColumnFamilyStorePerTree<'a, T, W>),
+    // a tree of the tree column family layout whose column family is not created yet, it's empty and can't be written
+    Absent,
+}
+
+impl<'a, T, W> TreeStore<'a, T, W> {
+    /// The prefix of the keys of all the records of the tree, the records of a tree column family are not prefixed.
+    pub fn prefix(&self) -> &[u8] {
+        match self {
+            TreeStore::Default(s) => s.prefix(),
+            TreeStore::ColumnFamily(s) => s.prefix(),
+            TreeStore::TreeColumnFamily(_) | TreeStore::Absent => &[],
+        }
+    }
+}
+
+impl<'a, V, T, W> StoreReadOps<V> for TreeStore<'a, T, W>
+where
+    V: Value + AsRef<[u8]> + From<DBVector>,
+    T: Get<ReadOptions> + GetCF<ReadOptions>,
+{
+    fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, Error> {
+        match self {
+            TreeStore::Default(s) => StoreReadOps::<V>::get_branch(s, branch_key),
+            TreeStore::ColumnFamily(s) => StoreReadOps::<V>::get_branch(s, branch_key),
+            TreeStore::TreeColumnFamily(s) => StoreReadOps::<V>::get_branch(s, branch_key),
+            TreeStore::Absent => Ok(None),
+        }
+    }
+
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<V>, Error> {
+        match self {
+            TreeStore::Default(s) => s.get_leaf(leaf_key),
+            TreeStore::ColumnFamily(s) => s.get_leaf(leaf_key),
+            TreeStore::TreeColumnFamily(s) => s.get_leaf(leaf_key),
+            TreeStore::Absent => Ok(None),
+        }
+    }
+}
+
+impl<'a, V, T, W> StoreWriteOps<V> for TreeStore<'a, T, W>
+where
+    V: Value + AsRef<[u8]> + From<DBVector>,
+    T: Delete<W> + Put<W> + DeleteCF<W> + PutCF<W>,
+{
+    fn insert_branch(&mut self, node_key: BranchKey, branch: BranchNode) -> Result<(), Error> {
+        match self {
+            TreeStore::Default(s) => StoreWriteOps::<V>::insert_branch(s, node_key, branch),
+            TreeStore::ColumnFamily(s) => StoreWriteOps::<V>::insert_branch(s, node_key, branch),
+            TreeStore::TreeColumnFamily(s) => {
+                StoreWriteOps::<V>::insert_branch(s, node_key, branch)
+            }
+            TreeStore::Absent => Err(absent_tree_error()),
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: V) -> Result<(), Error> {
+        match self {
+            TreeStore::Default(s) => s.insert_leaf(leaf_key, leaf),
+            TreeStore::ColumnFamily(s) => s.insert_leaf(leaf_key, leaf),
+            TreeStore::TreeColumnFamily(s) => s.insert_leaf(leaf_key, leaf),
+            TreeStore::Absent => Err(absent_tree_error()),
+        }
+    }
+
+    fn remove_branch(&mut self, node_key: &BranchKey) -> Result<(), Error> {
+        match self {
+            TreeStore::Default(s) => StoreWriteOps::<V>::remove_branch(s, node_key),
+            TreeStore::ColumnFamily(s) => StoreWriteOps::<V>::remove_branch(s, node_key),
+            TreeStore::TreeColumnFamily(s) => StoreWriteOps::<V>::remove_branch(s, node_key),
+            TreeStore::Absent => Err(absent_tree_error()),
+        }
+    }
+
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error> {
+        match self {
+            TreeStore::Default(s) => StoreWriteOps::<V>::remove_leaf(s, leaf_key),
+            TreeStore::ColumnFamily(s) => StoreWriteOps::<V>::remove_leaf(s, leaf_key),
+            TreeStore::TreeColumnFamily(s) => StoreWriteOps::<V>::remove_leaf(s, leaf_key),
+            TreeStore::Absent => Err(absent_tree_error()),
+        }
+    }
+}
+
+fn absent_tree_error() -> Error {
+    Error::Store("the column family of the tree does not exist".to_string())
+}